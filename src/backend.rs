@@ -0,0 +1,175 @@
+use crate::rules::{Counters, RuleSet};
+use crate::{iptables, nftables};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// Which netfilter frontend installs the rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backend {
+    Iptables,
+    Nftables,
+    /// cgroup socket hooks, only available for redirect mode.
+    Ebpf,
+}
+
+impl Backend {
+    /// Picks nftables on hosts where iptables is missing or only a shim over nf_tables,
+    /// and sticks to iptables when the legacy xtables backend is in use.
+    pub fn detect() -> Self {
+        let has_nft = (cmd_lib::run_fun! { nft --version }).is_ok();
+        let iptables_version = cmd_lib::run_fun! { iptables --version };
+        let backend = match iptables_version {
+            Ok(version) if !version.contains("nf_tables") => Backend::Iptables,
+            _ if has_nft => Backend::Nftables,
+            _ => Backend::Iptables,
+        };
+        tracing::debug!("detected {:?} backend", backend);
+        backend
+    }
+
+    pub fn install(&self, rules: &RuleSet) -> Result<()> {
+        match self {
+            Backend::Iptables => iptables::install(rules),
+            Backend::Nftables => nftables::install(rules),
+            Backend::Ebpf => Err(eyre!("the ebpf backend only supports redirect mode")),
+        }
+    }
+
+    /// Shell commands equivalent to [`Backend::install`], for `--dry-run`.
+    pub fn install_commands(&self, rules: &RuleSet) -> Vec<String> {
+        match self {
+            Backend::Iptables => iptables::install_commands(rules),
+            Backend::Nftables => vec![nftables::install_command(rules)],
+            Backend::Ebpf => Vec::new(),
+        }
+    }
+
+    /// Shell commands equivalent to [`Backend::uninstall`], for `--dry-run`.
+    pub fn uninstall_commands(&self, rules: &RuleSet) -> Vec<String> {
+        match self {
+            Backend::Iptables => iptables::uninstall_commands(rules),
+            Backend::Nftables => vec![nftables::uninstall_command(rules)],
+            Backend::Ebpf => Vec::new(),
+        }
+    }
+
+    /// Counters of every chain in `rules`. The ebpf backend has none.
+    pub fn counters(&self, rules: &RuleSet) -> Result<Vec<Counters>> {
+        match self {
+            Backend::Iptables => iptables::counters(rules),
+            Backend::Nftables => nftables::counters(rules),
+            Backend::Ebpf => Ok(Vec::new()),
+        }
+    }
+
+    /// Adds `ip` to the set `set` of `rules` for `timeout` seconds, or renews it.
+    pub fn add_to_set(&self, rules: &RuleSet, set: &str, ip: IpAddr, timeout: u32) -> Result<()> {
+        match self {
+            Backend::Iptables => iptables::add_to_set(set, ip, timeout),
+            Backend::Nftables => nftables::add_to_set(rules, set, ip, timeout),
+            Backend::Ebpf => Err(eyre!("the ebpf backend has no sets")),
+        }
+    }
+
+    pub fn uninstall(&self, rules: &RuleSet) -> Result<()> {
+        match self {
+            Backend::Iptables => iptables::uninstall(rules),
+            Backend::Nftables => nftables::uninstall(rules),
+            Backend::Ebpf => Ok(()),
+        }
+    }
+}
+
+/// Backend as given on the command line, `auto` is resolved with [`Backend::detect`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendChoice {
+    Auto,
+    Fixed(Backend),
+}
+
+impl BackendChoice {
+    pub fn resolve(self) -> Backend {
+        match self {
+            BackendChoice::Auto => Backend::detect(),
+            BackendChoice::Fixed(backend) => backend,
+        }
+    }
+}
+
+impl FromStr for BackendChoice {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(BackendChoice::Auto),
+            "iptables" => Ok(BackendChoice::Fixed(Backend::Iptables)),
+            "nftables" | "nft" => Ok(BackendChoice::Fixed(Backend::Nftables)),
+            "ebpf" | "bpf" => Ok(BackendChoice::Fixed(Backend::Ebpf)),
+            _ => Err(eyre!(
+                "unknown backend `{}`, expected `auto`, `iptables`, `nftables` or `ebpf`",
+                s
+            )),
+        }
+    }
+}
+
+/// Runs `program` with `input` on its stdin, failing with its stderr if it exits unsuccessfully.
+pub fn pipe_to(program: &str, args: &[&str], input: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Written from another thread, so a program that reports errors before it read all of its
+    // input can't block on a full stderr pipe. Closing stdin ends the input even if this failed.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_owned();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    let written = writer
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("writer panicked")));
+    if output.status.success() && written.is_ok() {
+        return Ok(());
+    }
+    let mut error = format!(
+        "{} {} failed with {}",
+        program,
+        args.join(" "),
+        output.status
+    );
+    if let Err(e) = written {
+        write!(error, ", writing its input failed: {}", e).unwrap();
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        write!(error, ": {}", stderr.trim()).unwrap();
+    }
+    Err(eyre!(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_report_stderr() {
+        let error = pipe_to("sh", &["-c", "echo rejected >&2; exit 1"], "")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("rejected"), "{}", error);
+        // Exits before reading its input, so writing it fails too.
+        let input = "x".repeat(1 << 20);
+        let error = pipe_to("sh", &["-c", "exec <&-; echo early >&2; exit 1"], &input)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("writing its input failed"), "{}", error);
+        assert!(error.contains("early"), "{}", error);
+        pipe_to("sh", &["-c", "cat >/dev/null"], &input).unwrap();
+    }
+}
@@ -0,0 +1,842 @@
+//! Redirect mode implemented with cgroup socket hooks instead of netfilter.
+//!
+//! The programs are tiny, so they are assembled here directly instead of requiring a BPF toolchain.
+//! `connect4`/`sendmsg4` rewrite the destination of the session's sockets to the local proxy and
+//! remember the original one by socket cookie, `getpeername4`/`recvmsg4` make the rewrite invisible
+//! to the program, and a `sock_ops` program publishes the original destination keyed by the
+//! client's source port so the proxy can look it up. The IPv6 hooks do the same for IPv4-mapped
+//! destinations of dual-stack sockets.
+
+use crate::guards::{SessionOptions, UdpPolicy};
+use crate::rules::{Cidr, Family};
+use eyre::{eyre, Result};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_LINK_CREATE: libc::c_long = 28;
+
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+const BPF_F_NO_PREALLOC: u32 = 1;
+const BPF_PROG_TYPE_SOCK_OPS: u32 = 13;
+const BPF_PROG_TYPE_CGROUP_SOCK_ADDR: u32 = 18;
+
+const BPF_CGROUP_SOCK_OPS: u32 = 3;
+const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+const BPF_CGROUP_INET6_CONNECT: u32 = 11;
+const BPF_CGROUP_UDP4_SENDMSG: u32 = 14;
+const BPF_CGROUP_UDP6_SENDMSG: u32 = 15;
+const BPF_CGROUP_UDP4_RECVMSG: u32 = 19;
+const BPF_CGROUP_UDP6_RECVMSG: u32 = 20;
+const BPF_CGROUP_INET4_GETPEERNAME: u32 = 29;
+const BPF_CGROUP_INET6_GETPEERNAME: u32 = 30;
+
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_MAP_UPDATE_ELEM: i32 = 2;
+const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+
+const BPF_SOCK_OPS_TCP_CONNECT_CB: i32 = 3;
+
+// Offsets into `struct bpf_sock_addr` and `struct bpf_sock_ops`.
+const SOCK_ADDR_USER_IP4: i16 = 4;
+const SOCK_ADDR_USER_IP6: i16 = 8;
+const SOCK_ADDR_USER_PORT: i16 = 24;
+const SOCK_ADDR_TYPE: i16 = 32;
+const SOCK_OPS_OP: i16 = 0;
+const SOCK_OPS_LOCAL_PORT: i16 = 68;
+
+const MAP_ENTRIES: u32 = 65536;
+const BPFFS: &str = "/sys/fs/bpf";
+
+/// Registers, `R1`..`R5` are helper arguments, `R6`..`R9` survive calls, `R10` is the frame pointer.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R9: u8 = 9;
+const R8: u8 = 8;
+const R10: u8 = 10;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+/// Minimal assembler with named forward jumps.
+#[derive(Default)]
+struct Asm {
+    insns: Vec<Insn>,
+    labels: HashMap<&'static str, usize>,
+    jumps: Vec<(usize, &'static str)>,
+}
+
+impl Asm {
+    fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) -> &mut Self {
+        self.insns.push(Insn {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        });
+        self
+    }
+
+    fn mov64_reg(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.emit(0xbf, dst, src, 0, 0)
+    }
+
+    fn mov64_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.emit(0xb7, dst, 0, 0, imm)
+    }
+
+    fn mov32_imm(&mut self, dst: u8, imm: u32) -> &mut Self {
+        self.emit(0xb4, dst, 0, 0, imm as i32)
+    }
+
+    fn and32_imm(&mut self, dst: u8, imm: u32) -> &mut Self {
+        self.emit(0x54, dst, 0, 0, imm as i32)
+    }
+
+    fn rsh64_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.emit(0x77, dst, 0, 0, imm)
+    }
+
+    /// Converts the lower 16 bits between host and network byte order, clearing the rest.
+    fn be16(&mut self, dst: u8) -> &mut Self {
+        self.emit(0xdc, dst, 0, 0, 16)
+    }
+
+    fn add64_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.emit(0x07, dst, 0, 0, imm)
+    }
+
+    fn ldx_w(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.emit(0x61, dst, src, off, 0)
+    }
+
+    fn ldx_dw(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.emit(0x79, dst, src, off, 0)
+    }
+
+    fn stx_w(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.emit(0x63, dst, src, off, 0)
+    }
+
+    fn stx_dw(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.emit(0x7b, dst, src, off, 0)
+    }
+
+    fn ld_map_fd(&mut self, dst: u8, map: &Map) -> &mut Self {
+        // BPF_PSEUDO_MAP_FD in the source register marks the immediate as a map fd.
+        self.emit(0x18, dst, 1, 0, map.fd.as_raw_fd());
+        self.emit(0, 0, 0, 0, 0)
+    }
+
+    fn call(&mut self, helper: i32) -> &mut Self {
+        self.emit(0x85, 0, 0, 0, helper)
+    }
+
+    fn exit(&mut self) -> &mut Self {
+        self.emit(0x95, 0, 0, 0, 0)
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jumps.push((self.insns.len(), label));
+        self.emit(code, dst, 0, 0, imm as i32)
+    }
+
+    fn jeq_imm(&mut self, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jump(0x15, dst, imm, label)
+    }
+
+    fn jne_imm(&mut self, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jump(0x55, dst, imm, label)
+    }
+
+    /// Compares only the lower 32 bits, so immediates with the sign bit set work as expected.
+    fn jeq32_imm(&mut self, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jump(0x16, dst, imm, label)
+    }
+
+    fn jne32_imm(&mut self, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jump(0x56, dst, imm, label)
+    }
+
+    fn jle_imm(&mut self, dst: u8, imm: u32, label: &'static str) -> &mut Self {
+        self.jump(0xb5, dst, imm, label)
+    }
+
+    /// Like [`Asm::jne32_imm`], but jumps over the next `skip` instructions instead of to a label.
+    fn jne32_skip(&mut self, dst: u8, imm: u32, skip: i16) -> &mut Self {
+        self.emit(0x56, dst, 0, skip, imm as i32)
+    }
+
+    fn jlt_skip(&mut self, dst: u8, imm: u32, skip: i16) -> &mut Self {
+        self.emit(0xa5, dst, 0, skip, imm as i32)
+    }
+
+    fn jgt_skip(&mut self, dst: u8, imm: u32, skip: i16) -> &mut Self {
+        self.emit(0x25, dst, 0, skip, imm as i32)
+    }
+
+    fn ja(&mut self, label: &'static str) -> &mut Self {
+        self.jump(0x05, 0, 0, label)
+    }
+
+    fn label(&mut self, label: &'static str) -> &mut Self {
+        self.labels.insert(label, self.insns.len());
+        self
+    }
+
+    fn finish(&mut self) -> Vec<Insn> {
+        for (at, label) in &self.jumps {
+            let target = self.labels[label];
+            self.insns[*at].off = (target as isize - *at as isize - 1) as i16;
+        }
+        self.insns.clone()
+    }
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u8]) -> std::io::Result<RawFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), attr.len()) };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret as RawFd)
+    }
+}
+
+/// Builds a zeroed `union bpf_attr` from `(offset, bytes)` pairs.
+fn attr(fields: &[(usize, &[u8])]) -> [u8; 128] {
+    let mut attr = [0u8; 128];
+    for (offset, bytes) in fields {
+        attr[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+    }
+    attr
+}
+
+fn path_cstring(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+struct Map {
+    fd: OwnedFd,
+}
+
+impl Map {
+    fn lru_hash(key_size: u32, value_size: u32) -> Result<Self> {
+        let mut attr = attr(&[
+            (0, &BPF_MAP_TYPE_LRU_HASH.to_ne_bytes()),
+            (4, &key_size.to_ne_bytes()),
+            (8, &value_size.to_ne_bytes()),
+            (12, &MAP_ENTRIES.to_ne_bytes()),
+        ]);
+        let fd =
+            bpf(BPF_MAP_CREATE, &mut attr).map_err(|e| eyre!("failed to create bpf map: {}", e))?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Longest prefix match of the ranges of `family` in `nets`, `None` if there are none.
+    fn lpm_trie(nets: &[Cidr], family: Family) -> Result<Option<Self>> {
+        let nets: Vec<&Cidr> = nets.iter().filter(|n| n.family() == family).collect();
+        if nets.is_empty() {
+            return Ok(None);
+        }
+        let len = match family {
+            Family::V4 => 4u32,
+            Family::V6 => 16,
+        };
+        let mut attr = attr(&[
+            (0, &BPF_MAP_TYPE_LPM_TRIE.to_ne_bytes()),
+            (4, &(4 + len).to_ne_bytes()),
+            (8, &1u32.to_ne_bytes()),
+            (12, &(nets.len() as u32).to_ne_bytes()),
+            (16, &BPF_F_NO_PREALLOC.to_ne_bytes()),
+        ]);
+        let fd =
+            bpf(BPF_MAP_CREATE, &mut attr).map_err(|e| eyre!("failed to create bpf map: {}", e))?;
+        let map = Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        for net in nets {
+            // key: prefix length in host byte order, then the address in network byte order
+            let mut key = (net.prefix as u32).to_ne_bytes().to_vec();
+            match net.ip {
+                IpAddr::V4(ip) => key.extend(ip.octets()),
+                IpAddr::V6(ip) => key.extend(ip.octets()),
+            }
+            map.update(&key, &[1])?;
+        }
+        Ok(Some(map))
+    }
+
+    fn update(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut attr = attr(&[
+            (0, &(self.fd.as_raw_fd() as u32).to_ne_bytes()),
+            (8, &(key.as_ptr() as u64).to_ne_bytes()),
+            (16, &(value.as_ptr() as u64).to_ne_bytes()),
+        ]);
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr)
+            .map_err(|e| eyre!("failed to update bpf map: {}", e))?;
+        Ok(())
+    }
+
+    fn pin(&self, path: &Path) -> Result<()> {
+        let path = path_cstring(path)?;
+        let mut attr = attr(&[
+            (0, &(path.as_ptr() as u64).to_ne_bytes()),
+            (8, &(self.fd.as_raw_fd() as u32).to_ne_bytes()),
+        ]);
+        bpf(BPF_OBJ_PIN, &mut attr).map_err(|e| eyre!("failed to pin bpf map: {}", e))?;
+        Ok(())
+    }
+}
+
+struct Program {
+    fd: OwnedFd,
+}
+
+impl Program {
+    fn load(prog_type: u32, attach_type: u32, insns: &[Insn]) -> Result<Self> {
+        let license = CString::new("Dual MIT/GPL").unwrap();
+        let mut log = vec![0u8; 1 << 16];
+        let mut attr = attr(&[
+            (0, &prog_type.to_ne_bytes()),
+            (4, &(insns.len() as u32).to_ne_bytes()),
+            (8, &(insns.as_ptr() as u64).to_ne_bytes()),
+            (16, &(license.as_ptr() as u64).to_ne_bytes()),
+            (24, &1u32.to_ne_bytes()),
+            (28, &(log.len() as u32).to_ne_bytes()),
+            (32, &(log.as_mut_ptr() as u64).to_ne_bytes()),
+            (68, &attach_type.to_ne_bytes()),
+        ]);
+        match bpf(BPF_PROG_LOAD, &mut attr) {
+            Ok(fd) => Ok(Self {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            }),
+            Err(e) => {
+                let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+                Err(eyre!(
+                    "failed to load bpf program for attach type {}: {}\n{}",
+                    attach_type,
+                    e,
+                    String::from_utf8_lossy(&log[..end])
+                ))
+            }
+        }
+    }
+
+    /// Attaches through a bpf link, which the kernel detaches as soon as the link fd is closed.
+    fn attach(&self, cgroup: &OwnedFd, attach_type: u32) -> Result<OwnedFd> {
+        let mut attr = attr(&[
+            (0, &(self.fd.as_raw_fd() as u32).to_ne_bytes()),
+            (4, &(cgroup.as_raw_fd() as u32).to_ne_bytes()),
+            (8, &attach_type.to_ne_bytes()),
+        ]);
+        let fd = bpf(BPF_LINK_CREATE, &mut attr)
+            .map_err(|e| eyre!("failed to attach bpf program to cgroup: {}", e))?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// `127.0.0.1` as stored in `user_ip4`.
+fn localhost() -> u32 {
+    u32::from_ne_bytes([127, 0, 0, 1])
+}
+
+/// A port as stored in `user_port`, network byte order in the first two bytes.
+fn port_be(port: u16) -> u32 {
+    let [hi, lo] = port.to_be_bytes();
+    u32::from_ne_bytes([hi, lo, 0, 0])
+}
+
+/// Where a program finds the IPv4 destination of a socket.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Socket {
+    /// `user_ip4` of an IPv4 socket.
+    V4,
+    /// The last word of an IPv4-mapped `user_ip6` (`::ffff:a.b.c.d`) of a dual-stack IPv6 socket,
+    /// the default of Java and many other runtimes.
+    Mapped,
+}
+
+impl Socket {
+    /// Offset of the IPv4 address in `struct bpf_sock_addr`.
+    fn ip4(self) -> i16 {
+        match self {
+            Socket::V4 => SOCK_ADDR_USER_IP4,
+            Socket::Mapped => SOCK_ADDR_USER_IP6 + 12,
+        }
+    }
+}
+
+/// Jumps to `label` unless `user_ip6` is IPv4-mapped.
+fn unless_mapped(asm: &mut Asm, label: &'static str) {
+    asm.ldx_w(R2, R6, SOCK_ADDR_USER_IP6)
+        .jne32_imm(R2, 0, label)
+        .ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 4)
+        .jne32_imm(R2, 0, label)
+        .ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 8)
+        .jne32_imm(R2, u32::from_ne_bytes([0, 0, 0xff, 0xff]), label);
+}
+
+/// Saves the destination in `R7`/`R8` under the socket cookie and points the socket at the proxy
+/// port in `R9`, as stored in `user_port`. A mapped address keeps its prefix, so only its IPv4
+/// part changes.
+fn redirect(asm: &mut Asm, cookies: &Map, socket: Socket) {
+    asm.mov64_reg(R1, R6)
+        .call(BPF_FUNC_GET_SOCKET_COOKIE)
+        .stx_dw(R10, R0, -8)
+        .stx_w(R10, R7, -16)
+        .stx_w(R10, R8, -12)
+        .ld_map_fd(R1, cookies)
+        .mov64_reg(R2, R10)
+        .add64_imm(R2, -8)
+        .mov64_reg(R3, R10)
+        .add64_imm(R3, -16)
+        .mov64_imm(R4, 0)
+        .call(BPF_FUNC_MAP_UPDATE_ELEM)
+        .mov32_imm(R2, localhost())
+        .stx_w(R6, R2, socket.ip4())
+        .stx_w(R6, R9, SOCK_ADDR_USER_PORT);
+}
+
+/// Loads the destination port in `R8` into `R2`, in host byte order.
+fn load_dport(asm: &mut Asm) {
+    // `R8` holds the port in network byte order in its first two bytes.
+    asm.mov64_reg(R2, R8);
+    if cfg!(target_endian = "little") {
+        asm.be16(R2);
+    } else {
+        asm.rsh64_imm(R2, 16);
+    }
+}
+
+/// Loads the destination into `R7`/`R8`, the context is expected in `R6`.
+fn load_destination(asm: &mut Asm, socket: Socket) {
+    asm.ldx_w(R7, R6, socket.ip4())
+        .ldx_w(R8, R6, SOCK_ADDR_USER_PORT);
+}
+
+/// Jumps to `allow` for loopback destinations.
+fn allow_loopback(asm: &mut Asm) {
+    asm.mov64_reg(R2, R7)
+        .and32_imm(R2, u32::from_ne_bytes([255, 0, 0, 0]))
+        .jeq_imm(R2, u32::from_ne_bytes([127, 0, 0, 0]), "allow");
+}
+
+/// `(mask, network)` of each 32 bit word of `net` covered by its prefix, as stored in
+/// `user_ip4`/`user_ip6`.
+fn prefix_words(net: &Cidr) -> Vec<(u32, u32)> {
+    let octets = match net.ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let mut words = Vec::new();
+    for (i, word) in octets.chunks(4).enumerate() {
+        let bits = (net.prefix as u32).saturating_sub(32 * i as u32).min(32);
+        if bits == 0 {
+            break;
+        }
+        let mask = u32::from_ne_bytes((u32::MAX << (32 - bits)).to_be_bytes());
+        words.push((
+            mask,
+            u32::from_ne_bytes([word[0], word[1], word[2], word[3]]),
+        ));
+    }
+    words
+}
+
+fn allow(asm: &mut Asm) -> Vec<Insn> {
+    asm.label("allow").mov64_imm(R0, 1).exit().finish()
+}
+
+/// Jumps to `label` if the IPv4 address in `R7` is in any of `nets`.
+fn match_v4(asm: &mut Asm, nets: &[Cidr], label: &'static str) {
+    for net in nets.iter().filter(|n| n.family() == Family::V4) {
+        match prefix_words(net).first() {
+            Some(&(mask, net)) => asm
+                .mov64_reg(R2, R7)
+                .and32_imm(R2, mask)
+                .jeq32_imm(R2, net, label),
+            None => asm.ja(label),
+        };
+    }
+}
+
+/// Jumps to `label` if the IPv4 address in `R7` is in `trie`.
+fn lookup_v4(asm: &mut Asm, trie: &Map, label: &'static str) {
+    asm.mov32_imm(R2, 32)
+        .stx_w(R10, R2, -32)
+        .stx_w(R10, R7, -28)
+        .ld_map_fd(R1, trie)
+        .mov64_reg(R2, R10)
+        .add64_imm(R2, -32)
+        .call(BPF_FUNC_MAP_LOOKUP_ELEM)
+        .jne_imm(R0, 0, label);
+}
+
+/// Ranges from `--bypass-file` and `--only-dst-file`, too many to compare one by one.
+#[derive(Default)]
+struct Tries {
+    bypass4: Option<Map>,
+    bypass6: Option<Map>,
+    only_dst4: Option<Map>,
+}
+
+impl Tries {
+    fn new(options: &SessionOptions) -> Result<Self> {
+        Ok(Self {
+            bypass4: Map::lpm_trie(&options.bypass_files, Family::V4)?,
+            bypass6: Map::lpm_trie(&options.bypass_files, Family::V6)?,
+            only_dst4: Map::lpm_trie(&options.only_dst_files, Family::V4)?,
+        })
+    }
+}
+
+/// Sends the IPv4 destination in `R7`/`R8` where it belongs. TCP goes to the proxy, DNS over UDP
+/// and TCP too with `--redirect-dns`, also to a stub resolver on loopback. With `--udp-policy
+/// reject` other UDP the proxy would get jumps to `reject`. Everything else jumps to `allow`.
+fn redirect_ipv4(
+    asm: &mut Asm,
+    cookies: &Map,
+    port: u16,
+    options: &SessionOptions,
+    tries: &Tries,
+    socket: Socket,
+) {
+    let reject_udp = options.udp_policy == UdpPolicy::Reject;
+    let udp = if reject_udp { "checks" } else { "allow" };
+    let dns_port = options.dns_port(port as u32) as u16;
+    asm.ldx_w(R2, R6, SOCK_ADDR_TYPE)
+        .jeq_imm(R2, libc::SOCK_STREAM as u32, "stream")
+        .jne_imm(R2, libc::SOCK_DGRAM as u32, "allow");
+    if options.redirect_dns {
+        asm.jne_imm(R8, port_be(53), udp)
+            .mov32_imm(R9, port_be(dns_port))
+            .ja("redirect");
+    } else if reject_udp {
+        asm.jeq_imm(R8, port_be(53), "allow");
+    } else {
+        asm.ja("allow");
+    }
+    asm.label("stream");
+    if options.redirect_dns {
+        asm.jne_imm(R8, port_be(53), "checks")
+            .mov32_imm(R9, port_be(dns_port))
+            .ja("redirect");
+    }
+    asm.label("checks");
+    allow_loopback(asm);
+    match_v4(asm, &options.bypass, "allow");
+    if let Some(trie) = &tries.bypass4 {
+        lookup_v4(asm, trie, "allow");
+    }
+    if !options.only_dst.is_empty() || !options.only_dst_files.is_empty() {
+        match_v4(asm, &options.only_dst, "only_dst");
+        if let Some(trie) = &tries.only_dst4 {
+            lookup_v4(asm, trie, "only_dst");
+        }
+        asm.ja("allow").label("only_dst");
+    }
+    if !options.only_dport.is_empty() {
+        load_dport(asm);
+        for ports in &options.only_dport {
+            asm.jlt_skip(R2, ports.start as u32, 1)
+                .jle_imm(R2, ports.end as u32, "only_dport");
+        }
+        asm.ja("allow").label("only_dport");
+    }
+    if reject_udp {
+        asm.ldx_w(R2, R6, SOCK_ADDR_TYPE)
+            .jeq_imm(R2, libc::SOCK_DGRAM as u32, "reject");
+    }
+    if !options.port_map.is_empty() {
+        load_dport(asm);
+        for mapping in &options.port_map {
+            for ports in &mapping.dports {
+                asm.jlt_skip(R2, ports.start as u32, 3)
+                    .jgt_skip(R2, ports.end as u32, 2)
+                    .mov32_imm(R9, port_be(mapping.port))
+                    .ja("redirect");
+            }
+        }
+    }
+    asm.mov32_imm(R9, port_be(options.tcp_port(port as u32) as u16))
+        .label("redirect");
+    redirect(asm, cookies, socket);
+    asm.ja("allow");
+}
+
+/// For `connect4` and `sendmsg4`, see [`redirect_ipv4`].
+fn connect4(cookies: &Map, port: u16, options: &SessionOptions, tries: &Tries) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov64_reg(R6, R1);
+    load_destination(&mut asm, Socket::V4);
+    redirect_ipv4(&mut asm, cookies, port, options, tries, Socket::V4);
+    if options.udp_policy == UdpPolicy::Reject {
+        asm.label("reject").mov64_imm(R0, 0).exit();
+    }
+    allow(&mut asm)
+}
+
+/// For `connect6` and `sendmsg6`. IPv4-mapped destinations are IPv4 traffic of a dual-stack
+/// socket and handled like in [`connect4`]. With the kill switch other destinations but `::1` and
+/// `bypass` are refused.
+fn connect6(cookies: &Map, port: u16, options: &SessionOptions, tries: &Tries) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov64_reg(R6, R1);
+    unless_mapped(&mut asm, "native");
+    load_destination(&mut asm, Socket::Mapped);
+    redirect_ipv4(&mut asm, cookies, port, options, tries, Socket::Mapped);
+    asm.label("native");
+    if options.block_ipv6 {
+        allow_bypass6(&mut asm, &options.bypass, tries);
+        asm.ldx_w(R2, R6, SOCK_ADDR_USER_IP6)
+            .jne32_imm(R2, 0, "reject")
+            .ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 4)
+            .jne32_imm(R2, 0, "reject")
+            .ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 8)
+            .jne32_imm(R2, 0, "reject")
+            .ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 12)
+            .jeq32_imm(R2, u32::from_ne_bytes([0, 0, 0, 1]), "allow");
+    } else {
+        asm.ja("allow");
+    }
+    if options.block_ipv6 || options.udp_policy == UdpPolicy::Reject {
+        asm.label("reject").mov64_imm(R0, 0).exit();
+    }
+    allow(&mut asm)
+}
+
+/// Reports the original destination instead of the proxy on one of `ports`, for `getpeername4`
+/// and `recvmsg4`, or their IPv6 counterparts with a mapped address.
+fn restore_peer(cookies: &Map, ports: &[u16], socket: Socket) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov64_reg(R6, R1);
+    if socket == Socket::Mapped {
+        unless_mapped(&mut asm, "allow");
+    }
+    asm.ldx_w(R2, R6, socket.ip4())
+        .jne_imm(R2, localhost(), "allow")
+        .ldx_w(R2, R6, SOCK_ADDR_USER_PORT);
+    for &port in ports {
+        asm.jeq_imm(R2, port_be(port), "restore");
+    }
+    asm.ja("allow")
+        .label("restore")
+        .mov64_reg(R1, R6)
+        .call(BPF_FUNC_GET_SOCKET_COOKIE)
+        .stx_dw(R10, R0, -8)
+        .ld_map_fd(R1, cookies)
+        .mov64_reg(R2, R10)
+        .add64_imm(R2, -8)
+        .call(BPF_FUNC_MAP_LOOKUP_ELEM)
+        .jeq_imm(R0, 0, "allow")
+        .ldx_w(R2, R0, 0)
+        .stx_w(R6, R2, socket.ip4())
+        .ldx_w(R2, R0, 4)
+        .stx_w(R6, R2, SOCK_ADDR_USER_PORT);
+    allow(&mut asm)
+}
+
+/// Jumps to `allow` for IPv6 destinations in `bypass`, for the kill switch.
+fn allow_bypass6(asm: &mut Asm, bypass: &[Cidr], tries: &Tries) {
+    for net in bypass.iter().filter(|n| n.family() == Family::V6) {
+        let words = prefix_words(net);
+        for (i, &(mask, net)) in words.iter().enumerate() {
+            // Each remaining word takes three instructions, then the jump to `allow`.
+            let skip = 3 * (words.len() - 1 - i) + 1;
+            asm.ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 4 * i as i16)
+                .and32_imm(R2, mask)
+                .jne32_skip(R2, net, skip as i16);
+        }
+        asm.ja("allow");
+    }
+    if let Some(trie) = &tries.bypass6 {
+        asm.mov32_imm(R2, 128).stx_w(R10, R2, -24);
+        for i in 0..4 {
+            asm.ldx_w(R2, R6, SOCK_ADDR_USER_IP6 + 4 * i)
+                .stx_w(R10, R2, -20 + 4 * i);
+        }
+        asm.ld_map_fd(R1, trie)
+            .mov64_reg(R2, R10)
+            .add64_imm(R2, -24)
+            .call(BPF_FUNC_MAP_LOOKUP_ELEM)
+            .jne_imm(R0, 0, "allow");
+    }
+}
+
+/// Once a redirected TCP socket has its source port, publish its original destination under it.
+fn sock_ops(cookies: &Map, orig_dst: &Map) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov64_reg(R6, R1)
+        .ldx_w(R2, R6, SOCK_OPS_OP)
+        .jne_imm(R2, BPF_SOCK_OPS_TCP_CONNECT_CB as u32, "allow")
+        .mov64_reg(R1, R6)
+        .call(BPF_FUNC_GET_SOCKET_COOKIE)
+        .stx_dw(R10, R0, -8)
+        .ld_map_fd(R1, cookies)
+        .mov64_reg(R2, R10)
+        .add64_imm(R2, -8)
+        .call(BPF_FUNC_MAP_LOOKUP_ELEM)
+        .jeq_imm(R0, 0, "allow")
+        .ldx_dw(R2, R0, 0)
+        .stx_dw(R10, R2, -16)
+        .ldx_w(R2, R6, SOCK_OPS_LOCAL_PORT)
+        .stx_w(R10, R2, -24)
+        .ld_map_fd(R1, orig_dst)
+        .mov64_reg(R2, R10)
+        .add64_imm(R2, -24)
+        .mov64_reg(R3, R10)
+        .add64_imm(R3, -16)
+        .mov64_imm(R4, 0)
+        .call(BPF_FUNC_MAP_UPDATE_ELEM);
+    allow(&mut asm)
+}
+
+/// Directory under the bpf filesystem holding the pinned maps of a session.
+pub fn pin_dir(session_name: &str) -> PathBuf {
+    Path::new(BPFFS).join(session_name)
+}
+
+/// Programs attached to one cgroup. Dropping it detaches everything and removes the pinned map.
+#[allow(unused)]
+pub struct Redirect {
+    cookies: Map,
+    orig_dst: Map,
+    tries: Tries,
+    programs: Vec<Program>,
+    links: Vec<OwnedFd>,
+    pin_dir: PathBuf,
+}
+
+impl Redirect {
+    pub fn attach(
+        cgroup_dir: &Path,
+        session_name: &str,
+        port: u16,
+        options: &SessionOptions,
+    ) -> Result<Self> {
+        let cgroup = std::fs::File::open(cgroup_dir)
+            .map_err(|e| eyre!("failed to open cgroup {}: {}", cgroup_dir.display(), e))?;
+        let cgroup = OwnedFd::from(cgroup);
+        // key: socket cookie, value: original { ipv4 address, port } in network byte order
+        let cookies = Map::lru_hash(8, 8)?;
+        // key: client source port in host byte order, same value
+        let orig_dst = Map::lru_hash(4, 8)?;
+        let tries = Tries::new(options)?;
+        let dns_port = options.dns_port(port as u32) as u16;
+        let mut proxy_ports = vec![options.tcp_port(port as u32) as u16, dns_port];
+        proxy_ports.extend(options.port_map.iter().map(|m| m.port));
+        proxy_ports.sort_unstable();
+        proxy_ports.dedup();
+
+        let mut hooks = vec![
+            (
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_INET4_CONNECT,
+                connect4(&cookies, port, options, &tries),
+            ),
+            (
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_INET4_GETPEERNAME,
+                restore_peer(&cookies, &proxy_ports, Socket::V4),
+            ),
+            (
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_INET6_CONNECT,
+                connect6(&cookies, port, options, &tries),
+            ),
+            (
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_INET6_GETPEERNAME,
+                restore_peer(&cookies, &proxy_ports, Socket::Mapped),
+            ),
+            (
+                BPF_PROG_TYPE_SOCK_OPS,
+                BPF_CGROUP_SOCK_OPS,
+                sock_ops(&cookies, &orig_dst),
+            ),
+        ];
+        if options.redirect_dns || options.udp_policy == UdpPolicy::Reject {
+            hooks.push((
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_UDP4_SENDMSG,
+                connect4(&cookies, port, options, &tries),
+            ));
+        }
+        if options.redirect_dns || options.udp_policy == UdpPolicy::Reject || options.block_ipv6 {
+            hooks.push((
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_UDP6_SENDMSG,
+                connect6(&cookies, port, options, &tries),
+            ));
+        }
+        if options.redirect_dns {
+            hooks.push((
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_UDP4_RECVMSG,
+                restore_peer(&cookies, &[dns_port], Socket::V4),
+            ));
+            hooks.push((
+                BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                BPF_CGROUP_UDP6_RECVMSG,
+                restore_peer(&cookies, &[dns_port], Socket::Mapped),
+            ));
+        }
+
+        let mut programs = Vec::new();
+        let mut links = Vec::new();
+        for (prog_type, attach_type, insns) in hooks {
+            let program = Program::load(prog_type, attach_type, &insns)?;
+            links.push(program.attach(&cgroup, attach_type)?);
+            programs.push(program);
+        }
+
+        let pin_dir = pin_dir(session_name);
+        std::fs::create_dir_all(&pin_dir)?;
+        if let Err(e) = orig_dst.pin(&pin_dir.join("orig_dst")) {
+            let _ = std::fs::remove_dir(&pin_dir);
+            return Err(e.wrap_err(format!("is {} a bpf filesystem?", BPFFS)));
+        }
+
+        Ok(Self {
+            cookies,
+            orig_dst,
+            tries,
+            programs,
+            links,
+            pin_dir,
+        })
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.pin_dir) {
+            tracing::warn!(
+                "failed to remove pinned bpf maps {}. error: {}",
+                self.pin_dir.display(),
+                e
+            );
+        }
+    }
+}
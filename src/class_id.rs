@@ -0,0 +1,126 @@
+//! Picking the class id of a session.
+//!
+//! The class id doubles as net_cls class id, fwmark, routing table and suffix of every chain name,
+//! so it has to be free in all of those places, not only among cproxy sessions.
+
+use crate::journal;
+use crate::netlink::Netlink;
+use crate::rules::Family;
+use crate::{iptables, nftables, plan};
+use eyre::{eyre, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// How many candidates are tried before giving up.
+const ATTEMPTS: u32 = 64;
+
+/// Routing tables `unspec`, `default`, `main` and `local`.
+const RESERVED: [u32; 4] = [0, 253, 254, 255];
+
+fn collect_net_cls(dir: &Path, ids: &mut HashSet<u32>) {
+    if let Ok(classid) = std::fs::read_to_string(dir.join("net_cls.classid")) {
+        if let Ok(id) = classid.trim().parse() {
+            ids.insert(id);
+        }
+    }
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                collect_net_cls(&entry.path(), ids);
+            }
+        }
+    }
+}
+
+/// Ids taken by sessions, chains, nftables tables, routing rules and net_cls cgroups.
+/// Sources that can't be read on this host are skipped.
+fn used() -> HashSet<u32> {
+    let mut ids = HashSet::new();
+    match journal::load_all() {
+        Ok(sessions) => ids.extend(sessions.iter().map(|(_, s)| s.class_id)),
+        Err(e) => tracing::debug!("failed to read journals. error: {}", e),
+    }
+    for family in [Family::V4, Family::V6] {
+        match iptables::list_chains(family, "cp_") {
+            Ok(chains) => ids.extend(chains.iter().filter_map(|c| plan::chain_owner(&c.name))),
+            Err(e) => tracing::debug!("failed to list {:?} chains. error: {}", family, e),
+        }
+    }
+    match nftables::list_tables("cproxy_") {
+        Ok(tables) => ids.extend(tables.iter().filter_map(|t| plan::session_owner(t))),
+        Err(e) => tracing::debug!("failed to list nftables tables. error: {}", e),
+    }
+    match Netlink::new().and_then(|mut netlink| netlink.rule_ids()) {
+        Ok(rule_ids) => ids.extend(rule_ids),
+        Err(e) => tracing::debug!("failed to list routing rules. error: {}", e),
+    }
+    let hier = cgroups_rs::hierarchies::auto();
+    if !hier.v2() {
+        collect_net_cls(&hier.root().join("net_cls"), &mut ids);
+    }
+    ids
+}
+
+/// 32 bit FNV-1a, unlike `DefaultHasher` the same in every build.
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// The `attempt`th candidate for a session that would like `preferred`. The sequence only
+/// depends on `preferred`, so the same host state always leads to the same id.
+fn candidate(preferred: u32, attempt: u32) -> u32 {
+    if attempt == 0 {
+        return preferred;
+    }
+    let mut bytes = preferred.to_le_bytes().to_vec();
+    bytes.extend(attempt.to_le_bytes());
+    fnv1a(&bytes)
+}
+
+/// The id [`allocate`] picks on a host where nothing uses any. `--dry-run` plans with it, reading
+/// what is in use needs root.
+pub fn unclaimed(preferred: u32) -> u32 {
+    (0..ATTEMPTS)
+        .map(|attempt| candidate(preferred, attempt))
+        .find(|id| !RESERVED.contains(id))
+        .unwrap_or(preferred)
+}
+
+/// Returns `preferred` if nothing uses it yet, otherwise the first free id of its candidates.
+/// `taken` are ids other sessions claimed while this one tried them, see [`journal::Journal`].
+pub fn allocate(preferred: u32, taken: &HashSet<u32>) -> Result<u32> {
+    let mut used = used();
+    used.extend(taken);
+    let id = (0..ATTEMPTS)
+        .map(|attempt| candidate(preferred, attempt))
+        .find(|id| !RESERVED.contains(id) && !used.contains(id))
+        .ok_or_else(|| {
+            eyre!(
+                "no free class id for this session after {} attempts, run `cproxy cleanup --orphans`",
+                ATTEMPTS
+            )
+        })?;
+    if id != preferred {
+        tracing::warn!(
+            "class id {} is already in use, using {} instead",
+            preferred,
+            id
+        );
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_are_stable_across_builds() {
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+        assert_eq!(candidate(1234, 0), 1234);
+        assert_eq!(candidate(1234, 1), fnv1a(&[0xd2, 0x04, 0, 0, 1, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,263 @@
+//! `cproxy cleanup`: removes what crashed sessions left behind.
+
+use crate::backend::Backend;
+use crate::journal::{self, Session};
+use crate::netlink::{Netlink, PolicyRoute};
+use crate::rules::{Family, RuleSet};
+use crate::{bpf, iptables, nftables, plan};
+use cgroups_rs::Cgroup;
+use eyre::Result;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Rules and routes that are already gone are fine, the session may have died halfway.
+fn is_missing(e: &eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<std::io::Error>()
+            .and_then(|e| e.raw_os_error()),
+        Some(libc::ENOENT) | Some(libc::ESRCH)
+    )
+}
+
+fn remove_rules(backend: Backend, rules: &RuleSet) -> Result<()> {
+    match backend {
+        Backend::Iptables => {
+            let mut present = Vec::new();
+            for family in [Family::V4, Family::V6] {
+                if rules.chains.iter().any(|c| c.family == family) {
+                    present.extend(iptables::list_chains(family, "cp_")?);
+                }
+            }
+            let mut rules = rules.clone();
+            rules.chains.retain(|c| {
+                present
+                    .iter()
+                    .any(|p| p.name == c.name && p.family == c.family && p.table == c.table)
+            });
+            if !rules.sets.is_empty() {
+                let sets = iptables::list_sets("cp_")?;
+                rules.sets.retain(|s| sets.contains(&s.name));
+            }
+            if !rules.chains.is_empty() || !rules.sets.is_empty() {
+                iptables::uninstall(&rules)?;
+                println!("removed iptables chains and ipsets of {}", rules.name);
+            }
+        }
+        Backend::Nftables => {
+            if nftables::list_tables(&rules.name)?.contains(&rules.name) {
+                nftables::uninstall(rules)?;
+                println!("removed nftables table {}", rules.name);
+            }
+        }
+        Backend::Ebpf => {}
+    }
+    Ok(())
+}
+
+fn remove_routes(routes: &[PolicyRoute]) -> Result<()> {
+    let mut netlink = Netlink::new()?;
+    for route in routes {
+        let mut removed = false;
+        for result in [netlink.delete_rule(route), netlink.delete_route(route)] {
+            match result {
+                Ok(()) => removed = true,
+                Err(e) if !is_missing(&e) => return Err(e),
+                Err(_) => {}
+            }
+        }
+        if removed {
+            println!(
+                "removed routing policy for fwmark {} ({:?})",
+                route.mark, route.family
+            );
+        }
+    }
+    Ok(())
+}
+
+fn remove_cgroup(path: &str) -> Result<()> {
+    let cg = Cgroup::load(cgroups_rs::hierarchies::auto(), path);
+    if !cg.exists() {
+        return Ok(());
+    }
+    for pid in cg.procs() {
+        cg.remove_task_by_tgid(pid)?;
+    }
+    cg.delete()?;
+    println!("removed cgroup {}", path);
+    Ok(())
+}
+
+fn remove_pin_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+        println!("removed {}", dir.display());
+    }
+    Ok(())
+}
+
+/// Removes everything listed in a journal, in the reverse order it was created.
+pub fn remove_session(session: &Session) -> Result<()> {
+    if let Some(dir) = &session.bpf_pin_dir {
+        remove_pin_dir(dir)?;
+    }
+    if let (Some(backend), Some(rules)) = (session.backend, &session.rules) {
+        remove_rules(backend, rules)?;
+    }
+    remove_routes(&session.routes)?;
+    if let Some(path) = &session.cgroup {
+        remove_cgroup(path)?;
+    }
+    Ok(())
+}
+
+/// Cleans up sessions whose owner is gone. Returns the class ids of the sessions still running.
+fn cleanup_stale() -> Result<HashSet<u32>> {
+    let mut live = HashSet::new();
+    let mut result = Ok(());
+    for (path, session) in journal::load_all()? {
+        if session.owner_alive() {
+            live.insert(session.class_id);
+            continue;
+        }
+        println!(
+            "cleaning up session {} of dead process {}",
+            session.class_id, session.owner
+        );
+        match remove_session(&session) {
+            Ok(()) => std::fs::remove_file(&path)?,
+            Err(e) => {
+                tracing::error!("failed to clean up {}. error: {}", path.display(), e);
+                live.insert(session.class_id);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    result.map(|_| live)
+}
+
+/// Whether an object of session `owner` (see [`plan::chain_owner`] and friends) belongs to no
+/// running session. Objects of other tools have no owner.
+fn orphaned(owner: Option<u32>, live: &HashSet<u32>) -> bool {
+    owner.is_some_and(|id| !live.contains(&id))
+}
+
+/// Looks for objects of sessions without a journal, by their names.
+fn cleanup_orphans(live: &HashSet<u32>) -> Result<()> {
+    for family in [Family::V4, Family::V6] {
+        let chains = match iptables::list_chains(family, "cp_") {
+            Ok(chains) => chains,
+            Err(e) => {
+                tracing::debug!("skipping {:?} iptables chains. error: {}", family, e);
+                continue;
+            }
+        };
+        let mut rules = RuleSet::new("orphans");
+        rules.chains = chains
+            .into_iter()
+            .filter(|c| orphaned(plan::chain_owner(&c.name), live))
+            .collect();
+        if !rules.chains.is_empty() {
+            iptables::uninstall(&rules)?;
+            for chain in &rules.chains {
+                println!("removed orphaned chain {} ({:?})", chain.name, family);
+            }
+        }
+    }
+
+    // After the chains, which may still reference them.
+    match iptables::list_sets("cp_") {
+        Ok(sets) => {
+            for name in sets
+                .iter()
+                .filter(|name| orphaned(plan::set_owner(name), live))
+            {
+                iptables::destroy_set(name)?;
+                println!("removed orphaned ipset {}", name);
+            }
+        }
+        Err(e) => tracing::debug!("skipping ipsets. error: {}", e),
+    }
+
+    match nftables::list_tables("cproxy_") {
+        Ok(tables) => {
+            for name in tables
+                .iter()
+                .filter(|name| orphaned(plan::session_owner(name), live))
+            {
+                nftables::uninstall(&RuleSet::new(name))?;
+                println!("removed orphaned nftables table {}", name);
+            }
+        }
+        Err(e) => tracing::debug!("skipping nftables tables. error: {}", e),
+    }
+
+    if let Ok(entries) = std::fs::read_dir(bpf::pin_dir("")) {
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if orphaned(plan::session_owner(&name), live) {
+                remove_pin_dir(&bpf::pin_dir(&name))?;
+            }
+        }
+    }
+
+    let hier = cgroups_rs::hierarchies::auto();
+    let root = if hier.v2() {
+        hier.root()
+    } else {
+        hier.root().join("net_cls")
+    };
+    if let Ok(entries) = std::fs::read_dir(root) {
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if orphaned(plan::cgroup_owner(&name), live) {
+                remove_cgroup(&name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn run(orphans: bool) -> Result<()> {
+    let live = cleanup_stale()?;
+    if orphans {
+        cleanup_orphans(&live)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_own_objects_are_orphans() {
+        let live: HashSet<u32> = [7].into();
+        for chain in ["cp_rd_out_1", "cp_tp_pre_1", "cp_bl_out_1"] {
+            assert!(orphaned(plan::chain_owner(chain), &live), "{}", chain);
+        }
+        for chain in [
+            "cp_foo_1",
+            "cp_rd_out_7",
+            "cp_rd_in_1",
+            "cp_rd_out_1x",
+            "xcp_rd_out_1",
+        ] {
+            assert!(!orphaned(plan::chain_owner(chain), &live), "{}", chain);
+        }
+        for set in ["cp_bypass_1", "cp_only6_1", "cp_domains_1"] {
+            assert!(orphaned(plan::set_owner(set), &live), "{}", set);
+        }
+        for set in ["cp_foo_1", "cp_bypass_7", "cp_bypass66_1", "cp_rd_out_1"] {
+            assert!(!orphaned(plan::set_owner(set), &live), "{}", set);
+        }
+        assert!(orphaned(plan::session_owner("cproxy_1"), &live));
+        assert!(!orphaned(plan::session_owner("cproxy_foo_1"), &live));
+        assert!(!orphaned(plan::session_owner("cproxy-1"), &live));
+        assert!(orphaned(plan::cgroup_owner("cproxy-1"), &live));
+        assert!(!orphaned(plan::cgroup_owner("cproxy-web-1"), &live));
+        assert!(!orphaned(plan::cgroup_owner("cproxy_1"), &live));
+    }
+}
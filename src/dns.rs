@@ -0,0 +1,453 @@
+//! DNS forwarder of `--proxy-domain` sessions. The session's DNS queries are redirected to it, it
+//! passes them on to the upstream server and adds the addresses answered for the configured
+//! domains to the session's sets, before the program gets the answer and connects.
+
+use crate::guards::SessionOptions;
+use crate::resolv;
+use crate::rules::Family;
+use eyre::{eyre, Result, WrapErr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// fwmark of the forwarder's own queries, the session's rules let them through.
+pub const MARK: u32 = 0x4350_0053;
+
+/// Addresses stay in the sets at least this many seconds, programs often keep using an answer a
+/// little longer than its TTL.
+const MIN_TTL: u32 = 60;
+
+/// How long to wait for the upstream server.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the listening threads check whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What `recv_from` returns when nothing came in before the read timeout.
+const POLL_ERRORS: [std::io::ErrorKind; 3] = [
+    std::io::ErrorKind::WouldBlock,
+    std::io::ErrorKind::TimedOut,
+    std::io::ErrorKind::Interrupted,
+];
+
+/// Queries forwarded at the same time.
+const WORKERS: usize = 8;
+
+/// Queries waiting for a worker, any more are dropped and the program asks again.
+const QUEUE: usize = 64;
+
+/// Size of the DNS message header, anything shorter is not a query.
+const HEADER_LEN: usize = 12;
+
+/// A domain and all its subdomains, given as `example.com` or `*.example.com`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomainPattern(String);
+
+impl DomainPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        name.eq_ignore_ascii_case(&self.0)
+            || name.len() > self.0.len()
+                && name.as_bytes()[name.len() - self.0.len() - 1] == b'.'
+                && name[name.len() - self.0.len()..].eq_ignore_ascii_case(&self.0)
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "*.{}", self.0)
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let domain = s.trim().trim_start_matches("*.").trim_end_matches('.');
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if domain.is_empty()
+            || domain
+                .split('.')
+                .any(|l| l.is_empty() || !l.chars().all(valid))
+        {
+            return Err(eyre!("invalid domain `{}`", s));
+        }
+        Ok(Self(domain.to_ascii_lowercase()))
+    }
+}
+
+/// Reads the name at `pos` of `msg`, following compression pointers. Returns it with the
+/// position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer has to go backwards, so this many jumps mean a loop.
+    for _ in 0..128 {
+        let len = *msg.get(pos).ok_or_else(|| eyre!("truncated name"))? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            0xc0..=0xff => {
+                let low = *msg.get(pos + 1).ok_or_else(|| eyre!("truncated name"))? as usize;
+                let target = (len & 0x3f) << 8 | low;
+                if target >= pos {
+                    return Err(eyre!("invalid compression pointer"));
+                }
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            0x40..=0xbf => return Err(eyre!("unsupported label type")),
+            _ => {
+                let label = msg
+                    .get(pos + 1..pos + 1 + len)
+                    .ok_or_else(|| eyre!("truncated name"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+    Err(eyre!("name too long"))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16> {
+    match msg.get(pos..pos + 2) {
+        Some(&[a, b]) => Ok(u16::from_be_bytes([a, b])),
+        _ => Err(eyre!("truncated message")),
+    }
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32> {
+    Ok((read_u16(msg, pos)? as u32) << 16 | read_u16(msg, pos + 2)? as u32)
+}
+
+/// Addresses of the `A` and `AAAA` records in the response `msg` that belong to `domains`, with
+/// how long to keep them. A record belongs to them if its own name or the question matches, so
+/// the addresses behind a `CNAME` count too.
+pub fn learned(msg: &[u8], domains: &[DomainPattern]) -> Result<Vec<(IpAddr, u32)>> {
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut pos = 12;
+    let mut asked = false;
+    for _ in 0..questions {
+        let (name, end) = read_name(msg, pos)?;
+        asked |= domains.iter().any(|d| d.matches(&name));
+        // QTYPE and QCLASS
+        pos = end + 4;
+    }
+    let mut learned = Vec::new();
+    for _ in 0..answers {
+        let (name, end) = read_name(msg, pos)?;
+        let kind = read_u16(msg, end)?;
+        let ttl = read_u32(msg, end + 4)?;
+        let len = read_u16(msg, end + 8)? as usize;
+        let data = msg
+            .get(end + 10..end + 10 + len)
+            .ok_or_else(|| eyre!("truncated record"))?;
+        pos = end + 10 + len;
+        if !asked && !domains.iter().any(|d| d.matches(&name)) {
+            continue;
+        }
+        let ip = match (kind, data) {
+            (1, &[a, b, c, d]) => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+            (28, data) if data.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        learned.push((ip, ttl.max(MIN_TTL)));
+    }
+    Ok(learned)
+}
+
+/// The first `--override-dns` server the session can reach if given, otherwise the first
+/// nameserver of `/etc/resolv.conf` it can reach.
+fn upstream(options: &SessionOptions) -> Result<SocketAddr> {
+    if !options.override_dns.is_empty() {
+        return options
+            .override_dns
+            .iter()
+            .find(|d| options.families().contains(&Family::of(&d.ip)))
+            .map(|d| SocketAddr::new(d.ip, d.port.unwrap_or(53)))
+            .ok_or_else(|| eyre!("no --override-dns server of a proxied address family"));
+    }
+    let resolv_conf =
+        std::fs::read_to_string("/etc/resolv.conf").wrap_err("failed to read /etc/resolv.conf")?;
+    resolv::nameservers(&resolv_conf)
+        .into_iter()
+        .find(|ip| options.families().contains(&Family::of(ip)))
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| eyre!("no nameserver in /etc/resolv.conf, pass --override-dns"))
+}
+
+/// Sends `query` to `upstream` and waits for its response.
+fn forward(query: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    let unspecified = match upstream {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    nix::sys::socket::setsockopt(&socket, nix::sys::socket::sockopt::Mark, &MARK)?;
+    socket.connect(upstream)?;
+    socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    socket.send(query)?;
+    let mut response = vec![0; 65535];
+    loop {
+        let len = socket.recv(&mut response)?;
+        // Same query id, anything else is a stray or spoofed datagram.
+        if len >= HEADER_LEN && response[..2] == query[..2] {
+            response.truncate(len);
+            return Ok(response);
+        }
+    }
+}
+
+/// Called with every address learned, and how many seconds to keep it.
+pub type Learn = Arc<dyn Fn(IpAddr, u32) + Send + Sync>;
+
+/// Loopback sockets the session's DNS queries are redirected to.
+pub struct Forwarder {
+    sockets: Vec<UdpSocket>,
+    upstream: SocketAddr,
+    domains: Vec<DomainPattern>,
+}
+
+impl Forwarder {
+    /// Listens on the same unused port of `127.0.0.1`, and of `::1` if the session proxies IPv6.
+    pub fn bind(options: &SessionOptions) -> Result<Self> {
+        let upstream = upstream(options)?;
+        let mut last_error = None;
+        for _ in 0..16 {
+            let v4 = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+            let port = v4.local_addr()?.port();
+            let mut sockets = vec![v4];
+            if options.ipv6 {
+                match UdpSocket::bind((Ipv6Addr::LOCALHOST, port)) {
+                    Ok(v6) => sockets.push(v6),
+                    Err(e) => {
+                        last_error = Some(e);
+                        continue;
+                    }
+                }
+            }
+            return Ok(Self {
+                sockets,
+                upstream,
+                domains: options.proxy_domains.clone(),
+            });
+        }
+        Err(eyre!(
+            "no loopback port free for IPv4 and IPv6: {:?}",
+            last_error
+        ))
+    }
+
+    pub fn port(&self) -> u16 {
+        self.sockets[0]
+            .local_addr()
+            .map(|a| a.port())
+            .unwrap_or_default()
+    }
+
+    /// Answers queries until `stop` is dropped. The addresses of an answer are passed to `learn`
+    /// before the answer is passed on.
+    pub fn serve(self, stop: flume::Receiver<()>, learn: Learn) -> Result<Vec<JoinHandle<()>>> {
+        let (jobs, queue) = flume::bounded::<(Vec<u8>, SocketAddr, Arc<UdpSocket>)>(QUEUE);
+        let mut threads = Vec::new();
+        for _ in 0..WORKERS {
+            let queue = queue.clone();
+            let learn = learn.clone();
+            let domains = self.domains.clone();
+            let upstream = self.upstream;
+            // Ends once the listening threads are gone and the queue is drained.
+            threads.push(std::thread::spawn(move || {
+                for (query, client, socket) in queue.iter() {
+                    let response = match forward(&query, upstream) {
+                        Ok(response) => response,
+                        Err(e) => {
+                            tracing::warn!("dns query to {} failed. error: {}", upstream, e);
+                            continue;
+                        }
+                    };
+                    // TC bit, the program asks again over TCP, which is not snooped.
+                    if response[2] & 0x02 != 0 {
+                        tracing::warn!(
+                            "truncated dns answer from {}, addresses the program gets over TCP instead go direct",
+                            upstream
+                        );
+                    }
+                    match learned(&response, &domains) {
+                        Ok(learned) => learned.into_iter().for_each(|(ip, ttl)| learn(ip, ttl)),
+                        Err(e) => tracing::warn!("failed to parse dns response. error: {}", e),
+                    }
+                    if let Err(e) = socket.send_to(&response, client) {
+                        tracing::warn!("failed to answer dns query. error: {}", e);
+                    }
+                }
+            }));
+        }
+        for socket in self.sockets {
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let socket = Arc::new(socket);
+            let stop = stop.clone();
+            let jobs = jobs.clone();
+            threads.push(std::thread::spawn(move || {
+                let mut query = vec![0; 65535];
+                while !stop.is_disconnected() {
+                    let (len, client) = match socket.recv_from(&mut query) {
+                        Ok(received) => received,
+                        Err(e) if POLL_ERRORS.contains(&e.kind()) => continue,
+                        Err(e) => {
+                            tracing::error!("dns forwarder stopped listening. error: {}", e);
+                            break;
+                        }
+                    };
+                    if len < HEADER_LEN {
+                        continue;
+                    }
+                    let job = (query[..len].to_vec(), client, socket.clone());
+                    if let Err(flume::TrySendError::Full(_)) = jobs.try_send(job) {
+                        tracing::warn!("dns forwarder is busy, dropping query from {}", client);
+                    }
+                }
+            }));
+        }
+        Ok(threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    /// A response to `question` with the records `(name, type, ttl, data)`, names after the
+    /// first one compressed to a pointer at the question.
+    fn response(question: &str, records: &[(Option<&str>, u16, u32, &[u8])]) -> Vec<u8> {
+        let mut msg = vec![
+            0x12,
+            0x34,
+            0x81,
+            0x80,
+            0,
+            1,
+            0,
+            records.len() as u8,
+            0,
+            0,
+            0,
+            0,
+        ];
+        msg.extend(name(question));
+        msg.extend([0, 1, 0, 1]);
+        for (owner, kind, ttl, data) in records {
+            match owner {
+                Some(owner) => msg.extend(name(owner)),
+                None => msg.extend([0xc0, 12]),
+            }
+            msg.extend(kind.to_be_bytes());
+            msg.extend([0, 1]);
+            msg.extend(ttl.to_be_bytes());
+            msg.extend((data.len() as u16).to_be_bytes());
+            msg.extend(*data);
+        }
+        msg
+    }
+
+    #[test]
+    fn learns_addresses_of_matching_answers() {
+        let domains: Vec<DomainPattern> = ["*.github.com", "googleapis.com."]
+            .iter()
+            .map(|d| d.parse().unwrap())
+            .collect();
+        assert!(domains[0].matches("api.GitHub.com."));
+        assert!(domains[0].matches("github.com"));
+        assert!(!domains[0].matches("notgithub.com"));
+        assert!("exa mple.com".parse::<DomainPattern>().is_err());
+
+        let cdn = name("github.map.fastly.net");
+        let msg = response(
+            "www.github.com",
+            &[
+                (None, 5, 3600, &cdn),
+                (Some("github.map.fastly.net"), 1, 30, &[185, 199, 108, 133]),
+                (Some("github.map.fastly.net"), 28, 300, &[0x20; 16]),
+            ],
+        );
+        assert_eq!(
+            learned(&msg, &domains).unwrap(),
+            [
+                ("185.199.108.133".parse().unwrap(), MIN_TTL),
+                (
+                    "2020:2020:2020:2020:2020:2020:2020:2020".parse().unwrap(),
+                    300
+                ),
+            ]
+        );
+
+        let msg = response("example.com", &[(None, 1, 300, &[93, 184, 216, 34])]);
+        assert!(learned(&msg, &domains).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forwards_queries_and_learns_before_answering() {
+        if !nix::unistd::geteuid().is_root() {
+            // Marking the upstream socket needs CAP_NET_ADMIN.
+            return;
+        }
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let options = SessionOptions {
+            override_dns: vec![format!("{}", server.local_addr().unwrap()).parse().unwrap()],
+            proxy_domains: vec!["example.com".parse().unwrap()],
+            ..Default::default()
+        };
+        let forwarder = Forwarder::bind(&options).unwrap();
+        let port = forwarder.port();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (stop, stopped) = flume::bounded(0);
+        let threads = forwarder
+            .serve(stopped, {
+                let seen = seen.clone();
+                Arc::new(move |ip, ttl| seen.lock().unwrap().push((ip, ttl)))
+            })
+            .unwrap();
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client.set_read_timeout(Some(UPSTREAM_TIMEOUT)).unwrap();
+        // Too short for a header, never reaches the upstream server.
+        client
+            .send_to(&[0x12], (Ipv4Addr::LOCALHOST, port))
+            .unwrap();
+        let query = [0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        client.send_to(&query, (Ipv4Addr::LOCALHOST, port)).unwrap();
+        let mut buf = [0; 512];
+        let (len, forwarder_addr) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &query);
+        let answer = response("www.example.com", &[(None, 1, 600, &[192, 0, 2, 1])]);
+        server.send_to(&answer, forwarder_addr).unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &answer[..]);
+        assert_eq!(*seen.lock().unwrap(), [("192.0.2.1".parse().unwrap(), 600)]);
+
+        drop(stop);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
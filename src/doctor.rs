@@ -0,0 +1,516 @@
+//! `cproxy doctor`: what each mode needs from the host, and what is missing.
+//!
+//! The same checks run before a session is set up, so a missing kernel module is reported as
+//! such instead of as a failed iptables command.
+
+use crate::backend::Backend;
+use crate::guards::{SessionOptions, UdpPolicy};
+use crate::netlink::Netlink;
+use cgroups_rs::Subsystem;
+use eyre::{eyre, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+const MODES: [&str; 4] = ["redirect", "tproxy", "trace", "block"];
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_BPF: u32 = 39;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Ok,
+    Missing,
+    /// The host doesn't tell, e.g. a container without `/lib/modules`. Never blocks a session.
+    Unknown,
+}
+
+/// Something a mode needs from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Requirement {
+    Root,
+    NetAdmin,
+    BpfAdmin,
+    NetCls,
+    CgroupV2,
+    BpfFs,
+    Binary(&'static str),
+    /// Any of the kernel modules, later names are the ones of older kernels.
+    Module(&'static [&'static str]),
+    PolicyRouting,
+    LocalRoutes,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub state: State,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, state: State, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            state,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Effective capabilities of this process.
+fn capabilities() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("CapEff:"))?;
+    u64::from_str_radix(line.trim_start_matches("CapEff:").trim(), 16).ok()
+}
+
+fn capability(name: &str, caps: &[u32]) -> Check {
+    match capabilities() {
+        Some(eff) if caps.iter().any(|&cap| eff & (1 << cap) != 0) => {
+            Check::new(name, State::Ok, "")
+        }
+        Some(_) => Check::new(name, State::Missing, "not in the effective capabilities"),
+        None => Check::new(name, State::Unknown, "cannot read /proc/self/status"),
+    }
+}
+
+/// Loaded, built-in and loadable modules of the running kernel, `None` if there is no way to
+/// tell which modules are loadable.
+struct Modules {
+    loaded: HashSet<String>,
+    available: Option<HashSet<String>>,
+}
+
+impl Modules {
+    fn read() -> Self {
+        let loaded = std::fs::read_to_string("/proc/modules")
+            .map(|modules| {
+                modules
+                    .lines()
+                    .filter_map(|l| l.split_whitespace().next())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let dir = Path::new("/lib/modules").join(release.trim());
+        let mut available = None;
+        for list in ["modules.dep", "modules.builtin"] {
+            if let Ok(content) = std::fs::read_to_string(dir.join(list)) {
+                // `kernel/net/netfilter/xt_TPROXY.ko.zst: deps`
+                let names = content
+                    .lines()
+                    .filter_map(|l| l.split(':').next())
+                    .filter_map(|path| path.rsplit('/').next()?.split(".ko").next())
+                    .map(str::to_owned);
+                available.get_or_insert_with(HashSet::new).extend(names);
+            }
+        }
+        Self { loaded, available }
+    }
+
+    fn check(&self, names: &[&str]) -> Check {
+        let name = format!("module {}", names.join("/"));
+        // Module names use `_` once loaded, file names may use `-`.
+        let normalize = |n: &str| n.replace('-', "_");
+        let is_in = |set: &HashSet<String>| {
+            names
+                .iter()
+                .any(|n| set.iter().any(|m| normalize(m) == normalize(n)))
+        };
+        if is_in(&self.loaded) {
+            return Check::new(name, State::Ok, "loaded");
+        }
+        match &self.available {
+            Some(available) if is_in(available) => {
+                Check::new(name, State::Ok, "built in or loaded on demand")
+            }
+            Some(_) => Check::new(name, State::Missing, "not available in this kernel"),
+            None => Check::new(
+                name,
+                State::Unknown,
+                "not loaded, and the kernel's module list is not readable",
+            ),
+        }
+    }
+}
+
+/// How `iptables` is implemented on this host.
+pub fn iptables_flavor() -> Option<String> {
+    let version = cmd_lib::run_fun! { iptables --version }.ok()?;
+    Some(if version.contains("nf_tables") {
+        "nf_tables".to_owned()
+    } else {
+        "legacy".to_owned()
+    })
+}
+
+fn binary(name: &str) -> Check {
+    let found = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false);
+    if found {
+        Check::new(name, State::Ok, "")
+    } else {
+        Check::new(name, State::Missing, "not found in PATH")
+    }
+}
+
+fn bpf_fs() -> Check {
+    let name = "bpf filesystem";
+    match std::fs::read_to_string("/proc/mounts") {
+        Ok(mounts) if mounts.lines().any(|l| l.starts_with("bpf /sys/fs/bpf ")) => {
+            Check::new(name, State::Ok, "mounted at /sys/fs/bpf")
+        }
+        Ok(_) => Check::new(name, State::Missing, "not mounted at /sys/fs/bpf"),
+        Err(e) => Check::new(name, State::Unknown, e.to_string()),
+    }
+}
+
+fn policy_routing() -> Check {
+    let name = "policy routing";
+    match Netlink::new().and_then(|mut netlink| netlink.rule_ids()) {
+        Ok(_) => Check::new(name, State::Ok, ""),
+        Err(e) => Check::new(name, State::Missing, e.to_string()),
+    }
+}
+
+fn local_routes() -> Check {
+    let name = "local routes";
+    match Netlink::new().and_then(|mut netlink| netlink.local_routes()) {
+        Ok(0) => Check::new(name, State::Missing, "no `local` routes in the local table"),
+        Ok(_) => Check::new(name, State::Ok, ""),
+        Err(e) => Check::new(name, State::Unknown, e.to_string()),
+    }
+}
+
+impl Requirement {
+    fn check(&self, modules: &Modules) -> Check {
+        let hier = cgroups_rs::hierarchies::auto();
+        match *self {
+            Requirement::Root => {
+                if nix::unistd::geteuid().is_root() {
+                    Check::new("root", State::Ok, "")
+                } else {
+                    Check::new("root", State::Missing, "run cproxy with sudo")
+                }
+            }
+            Requirement::NetAdmin => capability("CAP_NET_ADMIN", &[CAP_NET_ADMIN]),
+            Requirement::BpfAdmin => {
+                capability("CAP_BPF or CAP_SYS_ADMIN", &[CAP_BPF, CAP_SYS_ADMIN])
+            }
+            Requirement::NetCls => {
+                let name = "net_cls controller";
+                let mounted = hier
+                    .subsystems()
+                    .iter()
+                    .any(|s| matches!(s, Subsystem::NetCls(_)));
+                if mounted {
+                    Check::new(name, State::Ok, "")
+                } else {
+                    Check::new(
+                        name,
+                        State::Missing,
+                        "cgroup v1 without a net_cls hierarchy",
+                    )
+                }
+            }
+            Requirement::CgroupV2 => {
+                if hier.v2() {
+                    Check::new("cgroup v2", State::Ok, "")
+                } else {
+                    Check::new("cgroup v2", State::Missing, "the host uses cgroup v1")
+                }
+            }
+            Requirement::BpfFs => bpf_fs(),
+            Requirement::Binary(name) => binary(name),
+            Requirement::Module(names) => modules.check(names),
+            Requirement::PolicyRouting => policy_routing(),
+            Requirement::LocalRoutes => local_routes(),
+        }
+    }
+}
+
+/// What a `mode` session needs with `backend`.
+fn requirements(mode: &str, backend: Backend, options: &SessionOptions) -> Vec<Requirement> {
+    use Requirement::*;
+    let hier_v2 = cgroups_rs::hierarchies::auto().v2();
+    let mut needs = vec![Root, NetAdmin];
+    if backend == Backend::Ebpf {
+        // Redirect mode only, see `check_mode`.
+        needs.extend([BpfAdmin, CgroupV2, BpfFs]);
+        return needs;
+    }
+    if !hier_v2 {
+        needs.push(NetCls);
+    }
+    match backend {
+        Backend::Iptables => {
+            needs.push(Binary("iptables-restore"));
+            if options.ipv6 || options.block_ipv6 {
+                needs.push(Binary("ip6tables-restore"));
+            }
+            needs.push(Module(&["xt_cgroup"]));
+            match mode {
+                "redirect" => needs.extend([Module(&["iptable_nat"]), Module(&["xt_REDIRECT"])]),
+                // No xt_socket, `PREROUTING` only diverts what `OUTPUT` marked, never by `-m socket`.
+                "tproxy" => needs.extend([
+                    Module(&["iptable_mangle"]),
+                    Module(&["xt_TPROXY"]),
+                    Module(&["xt_mark"]),
+                ]),
+                // And the IPv6 ones of the kill switch, see below.
+                "block" => needs.extend([Module(&["iptable_filter"]), Module(&["ipt_REJECT"])]),
+                _ => needs.extend([
+                    Module(&["iptable_raw"]),
+                    Module(&["xt_LOG"]),
+                    Module(&["nf_log_syslog", "nf_log_ipv4"]),
+                ]),
+            }
+            let dports = !options.only_dport.is_empty() || !options.port_map.is_empty();
+            if dports && mode != "trace" {
+                needs.push(Module(&["xt_multiport"]));
+            }
+            let has_sets = !options.bypass_files.is_empty() || !options.only_dst_files.is_empty();
+            if has_sets && mode != "trace" {
+                needs.extend([
+                    Binary("ipset"),
+                    Module(&["xt_set"]),
+                    Module(&["ip_set_hash_net"]),
+                ]);
+            }
+            if !options.proxy_domains.is_empty() {
+                needs.extend([
+                    Binary("ipset"),
+                    Module(&["xt_set"]),
+                    Module(&["ip_set_hash_ip"]),
+                    Module(&["xt_mark"]),
+                ]);
+                if mode == "tproxy" {
+                    needs.extend([Module(&["iptable_nat"]), Module(&["xt_REDIRECT"])]);
+                }
+            }
+            if !options.override_dns.is_empty() && mode != "trace" {
+                if mode == "tproxy" && options.proxy_domains.is_empty() {
+                    needs.push(Module(&["iptable_nat"]));
+                }
+                needs.push(Module(&["xt_nat"]));
+                if options.override_dns.len() > 1 {
+                    needs.push(Module(&["xt_statistic"]));
+                }
+            }
+            if options.block_ipv6 {
+                needs.extend([Module(&["ip6table_filter"]), Module(&["ip6t_REJECT"])]);
+            }
+            if options.udp_policy != UdpPolicy::Direct && mode == "redirect" {
+                needs.push(Module(&["iptable_filter"]));
+                if options.ipv6 {
+                    needs.push(Module(&["ip6table_filter"]));
+                }
+            }
+            if options.udp_policy == UdpPolicy::Reject && mode == "redirect" {
+                needs.push(Module(&["ipt_REJECT"]));
+                if options.ipv6 {
+                    needs.push(Module(&["ip6t_REJECT"]));
+                }
+            }
+            if options.log_blocked {
+                needs.extend([
+                    Module(&["xt_LOG"]),
+                    Module(&["nf_log_syslog", "nf_log_ipv4"]),
+                ]);
+            }
+        }
+        _ => {
+            needs.extend([Binary("nft"), Module(&["nf_tables"])]);
+            if hier_v2 {
+                // `socket cgroupv2`
+                needs.push(Module(&["nft_socket"]));
+            }
+            match mode {
+                "redirect" => needs.extend([Module(&["nft_chain_nat"]), Module(&["nft_redir"])]),
+                "tproxy" => needs.push(Module(&["nft_tproxy"])),
+                // nft_reject_inet like the kill switch, see below.
+                "block" => {}
+                _ => needs.extend([
+                    Module(&["nft_log"]),
+                    Module(&["nf_log_syslog", "nf_log_ipv4"]),
+                ]),
+            }
+            if options.block_ipv6 || (options.udp_policy == UdpPolicy::Reject && mode == "redirect")
+            {
+                needs.push(Module(&["nft_reject_inet"]));
+            }
+            if !options.proxy_domains.is_empty() && mode == "tproxy" {
+                needs.extend([Module(&["nft_chain_nat"]), Module(&["nft_redir"])]);
+            }
+            if !options.override_dns.is_empty() && mode != "trace" {
+                if mode == "tproxy" && options.proxy_domains.is_empty() {
+                    needs.push(Module(&["nft_chain_nat"]));
+                }
+                needs.push(Module(&["nft_nat"]));
+                if options.override_dns.len() > 1 {
+                    needs.push(Module(&["nft_numgen"]));
+                }
+            }
+            if options.log_blocked {
+                needs.extend([
+                    Module(&["nft_log"]),
+                    Module(&["nf_log_syslog", "nf_log_ipv4"]),
+                ]);
+            }
+        }
+    }
+    if mode == "tproxy" {
+        needs.extend([PolicyRouting, LocalRoutes]);
+    }
+    needs
+}
+
+/// Checks everything a session needs before it is set up. Only requirements that are known to
+/// be missing fail, the ones the host doesn't tell about are logged.
+pub fn preflight(mode: &str, backend: Backend, options: &SessionOptions) -> Result<()> {
+    let modules = Modules::read();
+    let mut missing = Vec::new();
+    for requirement in requirements(mode, backend, options) {
+        let check = requirement.check(&modules);
+        match check.state {
+            State::Ok => {}
+            State::Missing => missing.push(format!("{} ({})", check.name, check.detail)),
+            State::Unknown => tracing::debug!("cannot check {}: {}", check.name, check.detail),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(eyre!(
+        "{} mode with the {:?} backend needs {}, see `cproxy doctor`",
+        mode,
+        backend,
+        missing.join(", ")
+    ))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModeReport {
+    pub mode: String,
+    pub backend: Backend,
+    pub ready: bool,
+    /// Names of the checks this mode needs that failed.
+    pub missing: Vec<String>,
+    /// Names of the checks this mode needs that could not be done.
+    pub unknown: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub cgroup_v2: bool,
+    /// `legacy` or `nf_tables`, missing if there is no `iptables`.
+    pub iptables: Option<String>,
+    /// What `--backend` resolves to, the modes are checked with it.
+    pub backend: Backend,
+    pub checks: Vec<Check>,
+    pub modes: Vec<ModeReport>,
+}
+
+impl Report {
+    fn new(backend: Backend) -> Self {
+        let modules = Modules::read();
+        let mut checks: Vec<(Requirement, Check)> = Vec::new();
+        let mut modes = Vec::new();
+        // The ebpf backend only does redirect mode, see `check_mode`.
+        let mut combinations: Vec<(&str, Backend)> = MODES
+            .iter()
+            .filter(|_| backend != Backend::Ebpf)
+            .map(|&m| (m, backend))
+            .collect();
+        combinations.push(("redirect", Backend::Ebpf));
+        for (mode, mode_backend) in combinations {
+            let mut report = ModeReport {
+                mode: mode.to_owned(),
+                backend: mode_backend,
+                ready: true,
+                missing: Vec::new(),
+                unknown: Vec::new(),
+            };
+            // Without --ipv6, and with the IPv6 kill switch like a default session.
+            let options = SessionOptions {
+                block_ipv6: mode != "trace",
+                ..Default::default()
+            };
+            for requirement in requirements(mode, mode_backend, &options) {
+                let check = match checks.iter().find(|(r, _)| *r == requirement) {
+                    Some((_, check)) => check.clone(),
+                    None => {
+                        let check = requirement.check(&modules);
+                        checks.push((requirement, check.clone()));
+                        check
+                    }
+                };
+                match check.state {
+                    State::Ok => {}
+                    State::Missing => report.missing.push(check.name),
+                    State::Unknown => report.unknown.push(check.name),
+                }
+            }
+            report.ready = report.missing.is_empty();
+            modes.push(report);
+        }
+        Self {
+            cgroup_v2: cgroups_rs::hierarchies::auto().v2(),
+            iptables: iptables_flavor(),
+            backend,
+            checks: checks.into_iter().map(|(_, check)| check).collect(),
+            modes,
+        }
+    }
+
+    fn print(&self) {
+        let cgroup = if self.cgroup_v2 { "v2" } else { "v1" };
+        println!("cgroup:   {}", cgroup);
+        println!(
+            "iptables: {}",
+            self.iptables.as_deref().unwrap_or("not found")
+        );
+        println!("backend:  {:?}", self.backend);
+        println!();
+        for check in &self.checks {
+            let state = match check.state {
+                State::Ok => "ok",
+                State::Missing => "missing",
+                State::Unknown => "unknown",
+            };
+            if check.detail.is_empty() {
+                println!("[{:>7}] {}", state, check.name);
+            } else {
+                println!("[{:>7}] {}: {}", state, check.name, check.detail);
+            }
+        }
+        println!();
+        for mode in &self.modes {
+            let mut status = if mode.ready {
+                "ready".to_owned()
+            } else {
+                format!("missing {}", mode.missing.join(", "))
+            };
+            if !mode.unknown.is_empty() {
+                status += &format!(" ({} could not be checked)", mode.unknown.len());
+            }
+            println!("{} ({:?}): {}", mode.mode, mode.backend, status);
+        }
+    }
+}
+
+/// Reports the modes with `backend` (as `--backend` resolves it), and redirect mode with ebpf.
+pub fn run(backend: Backend, json: bool) -> Result<()> {
+    let report = Report::new(backend);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
@@ -0,0 +1,340 @@
+//! Everything a session changes on the host goes through an [`Executor`]. The guards only decide
+//! what to change and in which order, so they can be tested against [`Recorder`] without root.
+
+use crate::backend::Backend;
+use crate::bpf;
+use crate::guards::SessionOptions;
+use crate::netlink::{Monitor, Netlink, PolicyRoute, Removed};
+use crate::plan::CgroupSpec;
+use crate::rules::RuleSet;
+use cgroups_rs::cgroup_builder::CgroupBuilder;
+use cgroups_rs::{Cgroup, CgroupPid};
+use eyre::Result;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Reports deletions of the routing policy of a session, see [`Monitor`].
+pub trait RouteMonitor: Send {
+    /// Waits a short while for the rule or route of any of `routes` to be deleted.
+    fn next(&self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>>;
+    /// What of `routes` is gone, for when [`RouteMonitor::next`] may have lost notifications.
+    fn missing(&self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>>;
+}
+
+impl RouteMonitor for Monitor {
+    fn next(&self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+        Monitor::next(self, routes)
+    }
+
+    fn missing(&self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+        Netlink::new()?.missing(routes)
+    }
+}
+
+pub trait Executor: Send + Sync {
+    fn create_cgroup(&self, cgroup: &CgroupSpec) -> Result<()>;
+    fn add_task(&self, cgroup: &CgroupSpec, pid: u32) -> Result<()>;
+    /// Moves the processes still in the cgroup to its parent and deletes it.
+    fn delete_cgroup(&self, cgroup: &CgroupSpec) -> Result<()>;
+    fn install(&self, backend: Backend, rules: &RuleSet) -> Result<()>;
+    fn uninstall(&self, backend: Backend, rules: &RuleSet) -> Result<()>;
+    /// Adds `ip` to the set `set` of the installed `rules` for `timeout` seconds.
+    fn add_to_set(
+        &self,
+        backend: Backend,
+        rules: &RuleSet,
+        set: &str,
+        ip: IpAddr,
+        timeout: u32,
+    ) -> Result<()>;
+    fn add_rule(&self, route: &PolicyRoute) -> Result<()>;
+    fn delete_rule(&self, route: &PolicyRoute) -> Result<()>;
+    fn add_route(&self, route: &PolicyRoute) -> Result<()>;
+    fn delete_route(&self, route: &PolicyRoute) -> Result<()>;
+    fn monitor_routes(&self) -> Result<Box<dyn RouteMonitor>>;
+    /// Attaches the redirect programs to the cgroup. Dropping the result detaches them again.
+    fn attach_bpf(
+        &self,
+        cgroup: &CgroupSpec,
+        port: u16,
+        options: &SessionOptions,
+    ) -> Result<Box<dyn Drop>>;
+}
+
+/// The real host.
+pub struct System;
+
+impl Executor for System {
+    fn create_cgroup(&self, cgroup: &CgroupSpec) -> Result<()> {
+        CgroupBuilder::new(cgroup.path.as_str())
+            .network()
+            .class_id(cgroup.class_id as u64)
+            .done()
+            .build(cgroups_rs::hierarchies::auto())?;
+        Ok(())
+    }
+
+    fn add_task(&self, cgroup: &CgroupSpec, pid: u32) -> Result<()> {
+        let cg = Cgroup::load(cgroups_rs::hierarchies::auto(), cgroup.path.as_str());
+        cg.add_task_by_tgid(CgroupPid::from(pid as u64))?;
+        Ok(())
+    }
+
+    fn delete_cgroup(&self, cgroup: &CgroupSpec) -> Result<()> {
+        let cg = Cgroup::load(cgroups_rs::hierarchies::auto(), cgroup.path.as_str());
+        for t in cg.procs() {
+            let t_dbg_string = format!("{:?}", t);
+            if let Err(e) = cg.remove_task_by_tgid(t) {
+                tracing::error!(
+                    "failed to remove process from cgroup. pid: {}. error: {}",
+                    t_dbg_string,
+                    e
+                );
+            }
+        }
+        cg.delete()?;
+        Ok(())
+    }
+
+    fn install(&self, backend: Backend, rules: &RuleSet) -> Result<()> {
+        backend.install(rules)
+    }
+
+    fn uninstall(&self, backend: Backend, rules: &RuleSet) -> Result<()> {
+        backend.uninstall(rules)
+    }
+
+    fn add_to_set(
+        &self,
+        backend: Backend,
+        rules: &RuleSet,
+        set: &str,
+        ip: IpAddr,
+        timeout: u32,
+    ) -> Result<()> {
+        backend.add_to_set(rules, set, ip, timeout)
+    }
+
+    fn add_rule(&self, route: &PolicyRoute) -> Result<()> {
+        Netlink::new()?.add_rule(route)
+    }
+
+    fn delete_rule(&self, route: &PolicyRoute) -> Result<()> {
+        Netlink::new()?.delete_rule(route)
+    }
+
+    fn add_route(&self, route: &PolicyRoute) -> Result<()> {
+        Netlink::new()?.add_route(route)
+    }
+
+    fn delete_route(&self, route: &PolicyRoute) -> Result<()> {
+        Netlink::new()?.delete_route(route)
+    }
+
+    fn monitor_routes(&self) -> Result<Box<dyn RouteMonitor>> {
+        Ok(Box::new(Monitor::new(Duration::from_millis(500))?))
+    }
+
+    fn attach_bpf(
+        &self,
+        cgroup: &CgroupSpec,
+        port: u16,
+        options: &SessionOptions,
+    ) -> Result<Box<dyn Drop>> {
+        Ok(Box::new(bpf::Redirect::attach(
+            &cgroup.v2_dir()?,
+            &cgroup.session_name(),
+            port,
+            options,
+        )?))
+    }
+}
+
+#[cfg(test)]
+pub use fake::{Op, Recorder};
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use eyre::eyre;
+    use std::sync::{Arc, Mutex};
+
+    /// One change made through the [`Recorder`].
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Op {
+        CreateCgroup(CgroupSpec),
+        AddTask(String, u32),
+        DeleteCgroup(String),
+        Install(Backend, RuleSet),
+        Uninstall(Backend, RuleSet),
+        AddToSet(String, IpAddr, u32),
+        AddRule(PolicyRoute),
+        DeleteRule(PolicyRoute),
+        AddRoute(PolicyRoute),
+        DeleteRoute(PolicyRoute),
+        AttachBpf {
+            path: String,
+            port: u16,
+            options: Box<SessionOptions>,
+        },
+        DetachBpf(String),
+    }
+
+    /// Records every change instead of making it.
+    pub struct Recorder {
+        ops: Arc<Mutex<Vec<Op>>>,
+        failing: Mutex<Vec<Op>>,
+        removed: flume::Sender<Removal>,
+        removals: flume::Receiver<Removal>,
+    }
+
+    /// A deletion, and whether its notification got lost.
+    type Removal = (Removed, PolicyRoute, bool);
+
+    struct FakeMonitor {
+        removals: flume::Receiver<Removal>,
+        lost: Mutex<Vec<(Removed, PolicyRoute)>>,
+    }
+
+    impl RouteMonitor for FakeMonitor {
+        fn next(&self, _routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+            match self.removals.recv_timeout(Duration::from_millis(10)) {
+                Ok((removed, route, true)) => {
+                    self.lost.lock().unwrap().push((removed, route));
+                    Err(eyre!("notifications lost"))
+                }
+                Ok((removed, route, false)) => Ok(vec![(removed, route)]),
+                Err(_) => Ok(Vec::new()),
+            }
+        }
+
+        fn missing(&self, _routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+            Ok(std::mem::take(&mut *self.lost.lock().unwrap()))
+        }
+    }
+
+    struct FakeLinks {
+        path: String,
+        ops: Arc<Mutex<Vec<Op>>>,
+    }
+
+    impl Drop for FakeLinks {
+        fn drop(&mut self) {
+            let path = std::mem::take(&mut self.path);
+            self.ops.lock().unwrap().push(Op::DetachBpf(path));
+        }
+    }
+
+    impl Recorder {
+        pub fn new() -> Arc<Self> {
+            let (removed, removals) = flume::unbounded();
+            Arc::new(Self {
+                ops: Arc::default(),
+                failing: Mutex::default(),
+                removed,
+                removals,
+            })
+        }
+
+        /// Everything done so far, in order.
+        pub fn ops(&self) -> Vec<Op> {
+            self.ops.lock().unwrap().clone()
+        }
+
+        /// Makes `op` fail, without recording it.
+        pub fn fail(&self, op: Op) {
+            self.failing.lock().unwrap().push(op);
+        }
+
+        /// Pretends someone else deleted the rule or route of `route`.
+        pub fn remove(&self, removed: Removed, route: PolicyRoute) {
+            self.removed.send((removed, route, false)).unwrap();
+        }
+
+        /// Like [`Recorder::remove`], but the monitor fails instead of reporting it.
+        pub fn remove_unnoticed(&self, removed: Removed, route: PolicyRoute) {
+            self.removed.send((removed, route, true)).unwrap();
+        }
+
+        fn record(&self, op: Op) -> Result<()> {
+            if self.failing.lock().unwrap().contains(&op) {
+                return Err(eyre!("{:?} failed", op));
+            }
+            self.ops.lock().unwrap().push(op);
+            Ok(())
+        }
+    }
+
+    impl Executor for Recorder {
+        fn create_cgroup(&self, cgroup: &CgroupSpec) -> Result<()> {
+            self.record(Op::CreateCgroup(cgroup.clone()))
+        }
+
+        fn add_task(&self, cgroup: &CgroupSpec, pid: u32) -> Result<()> {
+            self.record(Op::AddTask(cgroup.path.clone(), pid))
+        }
+
+        fn delete_cgroup(&self, cgroup: &CgroupSpec) -> Result<()> {
+            self.record(Op::DeleteCgroup(cgroup.path.clone()))
+        }
+
+        fn install(&self, backend: Backend, rules: &RuleSet) -> Result<()> {
+            self.record(Op::Install(backend, rules.clone()))
+        }
+
+        fn uninstall(&self, backend: Backend, rules: &RuleSet) -> Result<()> {
+            self.record(Op::Uninstall(backend, rules.clone()))
+        }
+
+        fn add_to_set(
+            &self,
+            _backend: Backend,
+            _rules: &RuleSet,
+            set: &str,
+            ip: IpAddr,
+            timeout: u32,
+        ) -> Result<()> {
+            self.record(Op::AddToSet(set.to_owned(), ip, timeout))
+        }
+
+        fn add_rule(&self, route: &PolicyRoute) -> Result<()> {
+            self.record(Op::AddRule(*route))
+        }
+
+        fn delete_rule(&self, route: &PolicyRoute) -> Result<()> {
+            self.record(Op::DeleteRule(*route))
+        }
+
+        fn add_route(&self, route: &PolicyRoute) -> Result<()> {
+            self.record(Op::AddRoute(*route))
+        }
+
+        fn delete_route(&self, route: &PolicyRoute) -> Result<()> {
+            self.record(Op::DeleteRoute(*route))
+        }
+
+        fn monitor_routes(&self) -> Result<Box<dyn RouteMonitor>> {
+            Ok(Box::new(FakeMonitor {
+                removals: self.removals.clone(),
+                lost: Mutex::default(),
+            }))
+        }
+
+        fn attach_bpf(
+            &self,
+            cgroup: &CgroupSpec,
+            port: u16,
+            options: &SessionOptions,
+        ) -> Result<Box<dyn Drop>> {
+            self.record(Op::AttachBpf {
+                path: cgroup.path.clone(),
+                port,
+                options: Box::new(options.clone()),
+            })?;
+            Ok(Box::new(FakeLinks {
+                path: cgroup.path.clone(),
+                ops: self.ops.clone(),
+            }))
+        }
+    }
+}
@@ -1,88 +1,286 @@
-use cgroups_rs::cgroup_builder::CgroupBuilder;
-use cgroups_rs::{Cgroup, CgroupPid};
-use eyre::Result;
+use crate::backend::Backend;
+use crate::bpf;
+use crate::class_id;
+use crate::dns::{self, DomainPattern};
+use crate::executor::Executor;
+use crate::journal::Journal;
+use crate::netlink::{PolicyRoute, Removed, TableRange};
+use crate::plan::{self, CgroupSpec};
+use crate::rules::{Cidr, Destination, Family, Mark, PortMapping, PortRange, RuleSet};
+use eyre::{eyre, Result};
+use flume::RecvTimeoutError;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
+/// What happens to UDP traffic that redirect mode can't proxy, everything but DNS.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UdpPolicy {
+    /// It goes out directly.
+    #[default]
+    Direct,
+    /// It is refused, so programs like browsers fall back to TCP right away.
+    Reject,
+    /// It is silently dropped.
+    Drop,
+}
+
+impl FromStr for UdpPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "direct" => Ok(UdpPolicy::Direct),
+            "reject" => Ok(UdpPolicy::Reject),
+            "drop" => Ok(UdpPolicy::Drop),
+            _ => Err(eyre!(
+                "unknown udp policy `{}`, expected `direct`, `reject` or `drop`",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings shared by all guards of a session.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Redirect DNS traffic to the proxy port as well, for redirect mode.
+    pub redirect_dns: bool,
+    /// Send DNS traffic to these servers instead, spread over the ones of each address family.
+    pub override_dns: Vec<Destination>,
+    /// Also proxy IPv6 traffic. Otherwise only IPv4 rules are installed.
+    pub ipv6: bool,
+    /// Reject IPv6 traffic of the session that is not proxied, so it can't leak around the proxy.
+    pub block_ipv6: bool,
+    /// Routing table for tproxy mode, the class id if not given.
+    pub route_table: Option<TableRange>,
+    /// Priority of the tproxy `ip rule`, chosen by the kernel if not given.
+    pub rule_priority: Option<u32>,
+    /// Destinations that are never sent to the proxy.
+    pub bypass: Vec<Cidr>,
+    /// More of them, from `--bypass-file`. Matched through a kernel set, there may be thousands.
+    pub bypass_files: Vec<Cidr>,
+    /// Only send traffic to these ranges to the proxy, if any.
+    pub only_dst: Vec<Cidr>,
+    /// More of them, from `--only-dst-file`, like [`SessionOptions::bypass_files`].
+    pub only_dst_files: Vec<Cidr>,
+    /// Only send traffic to these destination ports to the proxy, if any.
+    pub only_dport: Vec<PortRange>,
+    /// Only send traffic to addresses these domains resolve to to the proxy, if any. Learned by
+    /// the [`dns::Forwarder`] the session's DNS queries go through.
+    pub proxy_domains: Vec<DomainPattern>,
+    /// Loopback port of that forwarder, once it listens.
+    pub snoop_port: u16,
+    /// Proxy port for TCP, the session's port if not given.
+    pub tcp_port: Option<u16>,
+    /// Proxy port for UDP, the session's port if not given.
+    pub udp_port: Option<u16>,
+    /// Proxy port for DNS, the UDP one if not given.
+    pub dns_port: Option<u16>,
+    /// Proxy ports for traffic to some destination ports, the first matching one wins.
+    pub port_map: Vec<PortMapping>,
+    /// UDP the session would proxy if it could, for redirect mode.
+    pub udp_policy: UdpPolicy,
+    /// Let the session reach loopback, for block mode.
+    pub allow_loopback: bool,
+    /// Destinations block mode lets the session reach.
+    pub allow_dst: Vec<Cidr>,
+    /// Log what block mode rejects, the way trace mode logs traffic.
+    pub log_blocked: bool,
+}
+
+impl SessionOptions {
+    pub fn families(&self) -> Vec<Family> {
+        if self.ipv6 {
+            vec![Family::V4, Family::V6]
+        } else {
+            vec![Family::V4]
+        }
+    }
+
+    /// Where TCP goes if no [`SessionOptions::port_map`] entry matches.
+    pub fn tcp_port(&self, port: u32) -> u32 {
+        self.tcp_port.map_or(port, u32::from)
+    }
+
+    /// Where UDP goes if no [`SessionOptions::port_map`] entry matches.
+    pub fn udp_port(&self, port: u32) -> u32 {
+        self.udp_port.map_or(port, u32::from)
+    }
+
+    /// Where DNS goes, before any [`SessionOptions::port_map`] entry.
+    pub fn dns_port(&self, port: u32) -> u32 {
+        self.dns_port.map_or_else(|| self.udp_port(port), u32::from)
+    }
+}
+
+/// Finds where the unified cgroup hierarchy is mounted.
+pub fn cgroup2_mount() -> Result<PathBuf> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    mountinfo
+        .lines()
+        .find_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            if fs.split_whitespace().next() == Some("cgroup2") {
+                mount.split_whitespace().nth(4).map(PathBuf::from)
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| eyre!("cgroup2 filesystem is not mounted"))
+}
+
 #[allow(unused)]
 pub struct CGroupGuard {
     pub pid: Option<u32>,
-    pub cg: Cgroup,
-    pub cg_path: String,
-    pub class_id: u32,
-    pub hier_v2: bool,
+    pub spec: CgroupSpec,
+    /// Shared with the guards built on top of this one.
+    pub executor: Arc<dyn Executor>,
+    /// Dropped after the cgroup is deleted, so it outlives everything else of the session.
+    pub journal: Journal,
 }
 
 impl CGroupGuard {
-    pub fn new(pid: u32) -> Result<Self> {
-        let hier = cgroups_rs::hierarchies::auto();
-        let hier_v2 = hier.v2();
-        let class_id = pid;
-        let cg_path = format!("cproxy-{}", pid);
-        let cg: Cgroup = CgroupBuilder::new(cg_path.as_str())
-            .network()
-            .class_id(class_id as u64)
-            .done()
-            .build(hier)?;
-        cg.add_task_by_tgid(CgroupPid::from(pid as u64))
-            .expect("add task failed");
-        Ok(Self {
-            pid: Some(pid),
-            hier_v2,
-            cg,
-            cg_path,
-            class_id,
-        })
+    pub fn new(pid: u32, executor: Arc<dyn Executor>) -> Result<Self> {
+        let (spec, journal) =
+            Self::claim(|taken| CgroupSpec::for_pid(pid, |id| class_id::allocate(id, taken)))?;
+        Self::create(spec, Some(pid), journal, executor)
     }
 
-    pub fn from_path(path: &str) -> Result<Self> {
-        let hier = cgroups_rs::hierarchies::auto();
-        let hier_v2 = hier.v2();
-        // Use path hash as class_id to avoid conflicts
-        let class_id = {
-            use std::hash::{Hash, Hasher};
-            let mut hasher = std::collections::hash_map::DefaultHasher::new();
-            path.hash(&mut hasher);
-            hasher.finish() as u32
+    pub fn from_path(path: &str, executor: Arc<dyn Executor>) -> Result<Self> {
+        let (spec, journal) =
+            Self::claim(|taken| CgroupSpec::for_path(path, |id| class_id::allocate(id, taken)))?;
+        Self::create(spec, None, journal, executor)
+    }
+
+    /// Claims the class id `spec` picks by creating the session's journal. If a session that
+    /// started at the same time got it first, the next free one is picked.
+    fn claim(spec: impl Fn(&HashSet<u32>) -> Result<CgroupSpec>) -> Result<(CgroupSpec, Journal)> {
+        let mut taken = HashSet::new();
+        loop {
+            let spec = spec(&taken)?;
+            match Journal::create(&spec.session_name(), spec.class_id)? {
+                Some(journal) => return Ok((spec, journal)),
+                None => {
+                    tracing::debug!("class id {} was just taken", spec.class_id);
+                    taken.insert(spec.class_id);
+                }
+            }
+        }
+    }
+
+    /// Creates the cgroup of `spec` and moves `pid` into it.
+    pub fn create(
+        spec: CgroupSpec,
+        pid: Option<u32>,
+        mut journal: Journal,
+        executor: Arc<dyn Executor>,
+    ) -> Result<Self> {
+        journal.record(|s| s.cgroup = Some(spec.path.clone()))?;
+        executor.create_cgroup(&spec)?;
+        let guard = Self {
+            pid,
+            spec,
+            executor,
+            journal,
         };
+        if let Some(pid) = pid {
+            // The guard is built first, so the cgroup is deleted again if the task can't be moved in.
+            guard.executor.add_task(&guard.spec, pid)?;
+        }
+        Ok(guard)
+    }
+}
+
+impl CGroupGuard {
+    /// Removes `rules` of the session. A failure is logged and the journal kept for `cproxy
+    /// cleanup`, so the rest of the session is still torn down.
+    pub fn uninstall(&mut self, backend: Backend, rules: &RuleSet) {
+        if let Err(e) = self.executor.uninstall(backend, rules) {
+            tracing::error!("failed to remove rules. error: {}", e);
+            self.journal.keep();
+        }
+    }
+}
+
+impl Drop for CGroupGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.executor.delete_cgroup(&self.spec) {
+            tracing::warn!("failed to delete cgroup. error: {}", e);
+            self.journal.keep();
+        }
+    }
+}
 
-        let cg = CgroupBuilder::new(path)
-            .network()
-            .class_id(class_id as u64)
-            .done()
-            .build(hier)?;
+/// Runs the [`dns::Forwarder`] of a `--proxy-domain` session, filling the sets of its rules.
+pub struct SnoopGuard {
+    stop_channel: Option<flume::Sender<()>>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
 
+impl SnoopGuard {
+    /// Starts a forwarder if the session has `--proxy-domain`s, and returns the options with its
+    /// port.
+    pub fn listen(options: &SessionOptions) -> Result<(Option<dns::Forwarder>, SessionOptions)> {
+        let mut options = options.clone();
+        if options.proxy_domains.is_empty() {
+            return Ok((None, options));
+        }
+        let forwarder = dns::Forwarder::bind(&options)?;
+        options.snoop_port = forwarder.port();
+        Ok((Some(forwarder), options))
+    }
+
+    pub fn new(
+        forwarder: dns::Forwarder,
+        rules: &RuleSet,
+        backend: Backend,
+        executor: Arc<dyn Executor>,
+    ) -> Result<Self> {
+        let (sender, receiver) = flume::bounded(0);
+        let stopped = receiver.clone();
+        let rules = rules.clone();
+        let threads = forwarder.serve(
+            receiver,
+            Arc::new(move |ip, ttl| {
+                let family = Family::of(&ip);
+                let set = rules.sets.iter().find(|s| s.timeout && s.family == family);
+                // Late answers may still come in while the session is torn down.
+                if let Some(set) = set.filter(|_| !stopped.is_disconnected()) {
+                    if let Err(e) = executor.add_to_set(backend, &rules, &set.name, ip, ttl) {
+                        tracing::error!("failed to add {} to {}. error: {}", ip, set.name, e);
+                    }
+                }
+            }),
+        )?;
         Ok(Self {
-            pid: None,
-            hier_v2,
-            cg,
-            cg_path: path.to_string(),
-            class_id,
+            stop_channel: Some(sender),
+            threads,
         })
     }
 }
 
-impl Drop for CGroupGuard {
+impl Drop for SnoopGuard {
     fn drop(&mut self) {
-        for t in self.cg.procs() {
-            let t_dbg_string = format!("{:?}", t);
-            if let Err(e) = self.cg.remove_task_by_tgid(t) {
-                tracing::error!(
-                    "failed to remove process from cgroup. pid: {}. error: {}",
-                    t_dbg_string,
-                    e
-                );
+        self.stop_channel.take();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                tracing::error!("dns forwarder panicked");
             }
         }
-        if let Err(e) = self.cg.delete() {
-            tracing::warn!("failed to delete cgroup. error: {}", e)
-        }
     }
 }
 
 #[allow(unused)]
 pub struct RedirectGuard {
     port: u32,
-    output_chain_name: String,
+    rules: RuleSet,
+    backend: Backend,
+    /// Stopped before the rules are removed.
+    snoop_guard: Option<SnoopGuard>,
     cgroup_guard: CGroupGuard,
     redirect_dns: bool,
 }
@@ -91,268 +289,328 @@ impl RedirectGuard {
     pub fn new(
         port: u32,
         output_chain_name: &str,
-        cgroup_guard: CGroupGuard,
-        redirect_dns: bool,
+        mut cgroup_guard: CGroupGuard,
+        backend: Backend,
+        options: &SessionOptions,
     ) -> Result<Self> {
+        let redirect_dns = options.redirect_dns;
         tracing::debug!(
-            "creating redirect guard on port {}, with redirect_dns: {}",
+            "creating redirect guard on port {}, with redirect_dns: {}, ipv6: {}, block_ipv6: {}",
             port,
-            redirect_dns
+            redirect_dns,
+            options.ipv6,
+            options.block_ipv6
         );
-        let class_id = cgroup_guard.class_id;
-        let cgroup_path = cgroup_guard.cg_path.as_str();
-        (cmd_lib::run_cmd! {
-        iptables -t nat -N ${output_chain_name};
-        iptables -t nat -A OUTPUT -j ${output_chain_name};
-        iptables -t nat -A ${output_chain_name} -p udp -o lo -j RETURN;
-        iptables -t nat -A ${output_chain_name} -p tcp -o lo -j RETURN;
+        let (forwarder, options) = SnoopGuard::listen(options)?;
+        let rules = plan::redirect_rules(port, output_chain_name, &cgroup_guard.spec, &options);
+        cgroup_guard.journal.record(|s| {
+            s.backend = Some(backend);
+            s.rules = Some(rules.clone());
         })?;
+        cgroup_guard.executor.install(backend, &rules)?;
+        let mut guard = Self {
+            port,
+            rules,
+            backend,
+            snoop_guard: None,
+            cgroup_guard,
+            redirect_dns,
+        };
+        if let Some(forwarder) = forwarder {
+            let executor = guard.cgroup_guard.executor.clone();
+            guard.snoop_guard = Some(SnoopGuard::new(forwarder, &guard.rules, backend, executor)?);
+        }
 
-        if cgroup_guard.hier_v2 {
-            (cmd_lib::run_cmd! {
-                iptables -t nat -A ${output_chain_name} -p tcp -m cgroup --path ${cgroup_path} -j REDIRECT --to-ports ${port};
-            })?;
-            if redirect_dns {
-                (cmd_lib::run_cmd! {
-                    iptables -t nat -A ${output_chain_name} -p udp -m cgroup --path ${cgroup_path} --dport 53 -j REDIRECT --to-ports ${port};
-                })?;
-            }
-        } else {
-            (cmd_lib::run_cmd! {
-                iptables -t nat -A ${output_chain_name} -p tcp -m cgroup --cgroup ${class_id} -j REDIRECT --to-ports ${port};
-            })?;
-            if redirect_dns {
-                (cmd_lib::run_cmd! {
-                    iptables -t nat -A ${output_chain_name} -p udp -m cgroup --cgroup ${class_id} --dport 53 -j REDIRECT --to-ports ${port};
-                })?;
-            }
+        Ok(guard)
+    }
+}
+
+impl Drop for RedirectGuard {
+    fn drop(&mut self) {
+        self.snoop_guard.take();
+        self.cgroup_guard.uninstall(self.backend, &self.rules);
+    }
+}
+
+/// Redirect mode through cgroup socket hooks, see [`bpf`].
+#[allow(unused)]
+pub struct BpfRedirectGuard {
+    port: u32,
+    /// The attached [`bpf::Redirect`].
+    redirect: Option<Box<dyn Drop>>,
+    cgroup_guard: CGroupGuard,
+    redirect_dns: bool,
+}
+
+impl BpfRedirectGuard {
+    pub fn new(port: u32, mut cgroup_guard: CGroupGuard, options: &SessionOptions) -> Result<Self> {
+        let redirect_dns = options.redirect_dns;
+        tracing::debug!(
+            "creating bpf redirect guard on port {}, with redirect_dns: {}, block_ipv6: {}",
+            port,
+            redirect_dns,
+            options.block_ipv6
+        );
+        if !cgroup_guard.spec.hier_v2 {
+            return Err(eyre!(
+                "the ebpf backend needs the unified cgroup v2 hierarchy"
+            ));
         }
+        let bpf_port = u16::try_from(port).map_err(|_| eyre!("invalid port {}", port))?;
+        let pin_dir = bpf::pin_dir(&cgroup_guard.spec.session_name());
+        cgroup_guard.journal.record(|s| {
+            s.backend = Some(Backend::Ebpf);
+            s.bpf_pin_dir = Some(pin_dir);
+        })?;
+        let redirect = cgroup_guard
+            .executor
+            .attach_bpf(&cgroup_guard.spec, bpf_port, options)?;
 
         Ok(Self {
             port,
-            output_chain_name: output_chain_name.to_owned(),
+            redirect: Some(redirect),
             cgroup_guard,
             redirect_dns,
         })
     }
 }
 
-impl Drop for RedirectGuard {
+impl Drop for BpfRedirectGuard {
     fn drop(&mut self) {
-        let output_chain_name = &self.output_chain_name;
-
-        (cmd_lib::run_cmd! {
-          iptables -t nat -D OUTPUT -j ${output_chain_name};
-          iptables -t nat -F ${output_chain_name};
-          iptables -t nat -X ${output_chain_name};
-        })
-        .expect("drop iptables and cgroup failed");
+        // Closing the links detaches the programs before the cgroup goes away.
+        self.redirect.take();
     }
 }
 
-pub struct IpRuleGuardInner {
-    fwmark: u32,
-    table: u32,
-    guard_thread: std::thread::JoinHandle<()>,
-    stop_channel: flume::Sender<()>,
-}
+/// How long the routing policy watcher waits after an error before it looks again.
+const WATCH_RETRY: Duration = Duration::from_millis(100);
 
-#[allow(unused)]
 pub struct IpRuleGuard {
-    inner: Box<dyn Drop>,
+    routes: Vec<PolicyRoute>,
+    executor: Arc<dyn Executor>,
+    guard_thread: Option<std::thread::JoinHandle<()>>,
+    stop_channel: flume::Sender<()>,
 }
 
 impl IpRuleGuard {
-    pub fn new(fwmark: u32, table: u32) -> Self {
+    pub fn new(
+        mark: Mark,
+        table: u32,
+        priority: Option<u32>,
+        families: &[Family],
+        journal: &mut Journal,
+        executor: Arc<dyn Executor>,
+    ) -> Result<Self> {
+        let routes = plan::policy_routes(mark, table, priority, families);
+        journal.record(|s| s.routes = routes.clone())?;
+        // Subscribe before adding anything, so no deletion can slip through.
+        let monitor = executor.monitor_routes()?;
+        let mut added: Vec<(Removed, PolicyRoute)> = Vec::new();
+        for route in &routes {
+            let result = executor
+                .add_rule(route)
+                .map(|_| added.push((Removed::Rule, *route)))
+                .and_then(|_| executor.add_route(route))
+                .map(|_| added.push((Removed::Route, *route)));
+            if let Err(e) = result {
+                for (kind, route) in added.iter().rev() {
+                    let result = match kind {
+                        Removed::Rule => executor.delete_rule(route),
+                        Removed::Route => executor.delete_route(route),
+                    };
+                    if let Err(e) = result {
+                        tracing::error!("failed to roll back routing policy. error: {}", e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+
         let (sender, receiver) = flume::unbounded();
+        let watched = routes.clone();
+        let watcher = executor.clone();
         let thread = std::thread::spawn(move || {
-            (cmd_lib::run_cmd! {
-              ip rule add fwmark ${fwmark} table ${table};
-              ip route add local 0.0.0.0/0 dev lo table ${table};
-            })
-            .expect("set routing rules failed");
-            loop {
-                if (cmd_lib::run_fun! { ip rule list fwmark ${fwmark} })
-                    .expect("get routing rules failed")
-                    .is_empty()
-                {
-                    tracing::warn!("detected disappearing routing policy, possibly due to interruped network, resetting");
-                    (cmd_lib::run_cmd! {
-                      ip rule add fwmark ${fwmark} table ${table};
-                    })
-                    .expect("set routing rules failed");
-                }
-                if receiver.recv_timeout(Duration::from_secs(1)).is_ok() {
-                    break;
+            while receiver.try_recv().is_err() {
+                let removed = match monitor.next(&watched) {
+                    Ok(removed) => removed,
+                    Err(e) => {
+                        // E.g. ENOBUFS when notifications came in faster than they were read,
+                        // deletions may have been missed.
+                        tracing::warn!("failed to watch routing policy. error: {}", e);
+                        if receiver.recv_timeout(WATCH_RETRY) != Err(RecvTimeoutError::Timeout) {
+                            break;
+                        }
+                        match monitor.missing(&watched) {
+                            Ok(missing) => missing,
+                            Err(e) => {
+                                tracing::error!("failed to list routing policy. error: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                for (removed, route) in removed {
+                    tracing::warn!("detected disappearing routing policy ({:?} {:?}), possibly due to interruped network, resetting", removed, route.family);
+                    let result = match removed {
+                        Removed::Rule => watcher.add_rule(&route),
+                        Removed::Route => watcher.add_route(&route),
+                    };
+                    if let Err(e) = result {
+                        tracing::error!("failed to reset routing policy. error: {}", e);
+                    }
                 }
             }
         });
-        let inner = IpRuleGuardInner {
-            fwmark,
-            table,
-            guard_thread: thread,
+        Ok(Self {
+            routes,
+            executor,
+            guard_thread: Some(thread),
             stop_channel: sender,
-        };
-        let inner = with_drop::with_drop(inner, |x| {
-            x.stop_channel.send(()).unwrap();
-            x.guard_thread.join().unwrap();
-            let mark = x.fwmark;
-            let table = x.table;
-            (cmd_lib::run_cmd! {
-                ip rule delete fwmark ${mark} table ${table};
-                ip route delete local 0.0.0.0/0 dev lo table ${table};
-            })
-            .expect("drop routing rules failed");
-        });
-        Self {
-            inner: Box::new(inner),
+        })
+    }
+
+    /// Stops watching and removes the routing policy. Failures are logged, `false` if anything
+    /// was left behind.
+    pub fn remove(&mut self) -> bool {
+        if let Some(thread) = self.guard_thread.take() {
+            let _ = self.stop_channel.send(());
+            if thread.join().is_err() {
+                tracing::error!("routing policy watcher panicked");
+            }
         }
+        let mut removed = true;
+        for route in std::mem::take(&mut self.routes) {
+            for result in [
+                self.executor.delete_rule(&route),
+                self.executor.delete_route(&route),
+            ] {
+                if let Err(e) = result {
+                    tracing::error!("failed to remove routing policy. error: {}", e);
+                    removed = false;
+                }
+            }
+        }
+        removed
+    }
+}
+
+impl Drop for IpRuleGuard {
+    fn drop(&mut self) {
+        self.remove();
     }
 }
 
 #[allow(unused)]
 pub struct TProxyGuard {
     port: u32,
-    mark: u32,
-    output_chain_name: String,
-    prerouting_chain_name: String,
+    mark: Mark,
+    rules: RuleSet,
+    backend: Backend,
+    /// Stopped before the rules are removed.
+    snoop_guard: Option<SnoopGuard>,
     iprule_guard: IpRuleGuard,
     cgroup_guard: CGroupGuard,
-    override_dns: Option<String>,
+    override_dns: Vec<Destination>,
 }
 
 impl TProxyGuard {
     pub fn new(
         port: u32,
-        mark: u32,
+        mark: Mark,
         output_chain_name: &str,
         prerouting_chain_name: &str,
-        cgroup_guard: CGroupGuard,
-        override_dns: Option<String>,
+        mut cgroup_guard: CGroupGuard,
+        backend: Backend,
+        options: &SessionOptions,
     ) -> Result<Self> {
-        let class_id = cgroup_guard.class_id;
-        let cg_path = cgroup_guard.cg_path.as_str();
+        let override_dns = options.override_dns.clone();
         tracing::debug!(
-            "creating tproxy guard on port {}, with override_dns: {:?}",
+            "creating tproxy guard on port {}, with override_dns: {:?}, ipv6: {}, block_ipv6: {}",
             port,
-            override_dns
+            override_dns,
+            options.ipv6,
+            options.block_ipv6
         );
-        let iprule_guard = IpRuleGuard::new(mark, mark);
-        (cmd_lib::run_cmd! {
-
-        iptables -t mangle -N ${prerouting_chain_name};
-        iptables -t mangle -A PREROUTING -j ${prerouting_chain_name};
-        iptables -t mangle -A ${prerouting_chain_name} -p tcp -o lo -j RETURN;
-        iptables -t mangle -A ${prerouting_chain_name} -p udp -o lo -j RETURN;
-        iptables -t mangle -A ${prerouting_chain_name} -p udp -m mark --mark ${mark} -j TPROXY --on-ip 127.0.0.1 --on-port ${port};
-        iptables -t mangle -A ${prerouting_chain_name} -p tcp -m mark --mark ${mark} -j TPROXY --on-ip 127.0.0.1 --on-port ${port};
-
-        iptables -t mangle -N ${output_chain_name};
-        iptables -t mangle -A OUTPUT -j ${output_chain_name};
-        iptables -t mangle -A ${output_chain_name} -p tcp -o lo -j RETURN;
-        iptables -t mangle -A ${output_chain_name} -p udp -o lo -j RETURN;
-        })?;
-
-        if override_dns.is_some() {
-            (cmd_lib::run_cmd! {
-                iptables -t nat -N ${output_chain_name};
-                iptables -t nat -A OUTPUT -j ${output_chain_name};
-                iptables -t nat -A ${output_chain_name} -p udp -o lo -j RETURN;
-            })?;
-        }
-
-        if cgroup_guard.hier_v2 {
-            (cmd_lib::run_cmd! {
-                iptables -t mangle -A ${output_chain_name} -p tcp -m cgroup --path ${cg_path} -j MARK --set-mark ${mark};
-                iptables -t mangle -A ${output_chain_name} -p udp -m cgroup --path ${cg_path} -j MARK --set-mark ${mark};
-            })?;
-            if let Some(override_dns) = &override_dns {
-                (cmd_lib::run_cmd! {
-                    iptables -t nat -A ${output_chain_name} -p udp -m cgroup --path ${cg_path} --dport 53 -j DNAT --to-destination ${override_dns};
-                })?;
-            }
-        } else {
-            (cmd_lib::run_cmd! {
-                iptables -t mangle -A ${output_chain_name} -p tcp -m cgroup --cgroup ${class_id} -j MARK --set-mark ${mark};
-                iptables -t mangle -A ${output_chain_name} -p udp -m cgroup --cgroup ${class_id} -j MARK --set-mark ${mark};
-            })?;
-            if let Some(override_dns) = &override_dns {
-                (cmd_lib::run_cmd! {
-                    iptables -t nat -A ${output_chain_name} -p udp -m cgroup --cgroup ${class_id} --dport 53 -j DNAT --to-destination ${override_dns};
-                })?;
-            }
-        }
+        let table = plan::route_table(options, cgroup_guard.spec.class_id)?;
+        let iprule_guard = IpRuleGuard::new(
+            mark,
+            table,
+            options.rule_priority,
+            &options.families(),
+            &mut cgroup_guard.journal,
+            cgroup_guard.executor.clone(),
+        )?;
 
-        Ok(Self {
+        let (forwarder, options) = SnoopGuard::listen(options)?;
+        let rules = plan::tproxy_rules(
             port,
             mark,
-            output_chain_name: output_chain_name.to_owned(),
-            prerouting_chain_name: prerouting_chain_name.to_owned(),
+            output_chain_name,
+            prerouting_chain_name,
+            &cgroup_guard.spec,
+            &options,
+        );
+        cgroup_guard.journal.record(|s| {
+            s.backend = Some(backend);
+            s.rules = Some(rules.clone());
+        })?;
+        cgroup_guard.executor.install(backend, &rules)?;
+        let mut guard = Self {
+            port,
+            mark,
+            rules,
+            backend,
+            snoop_guard: None,
             iprule_guard,
             cgroup_guard,
             override_dns,
-        })
+        };
+        if let Some(forwarder) = forwarder {
+            let executor = guard.cgroup_guard.executor.clone();
+            guard.snoop_guard = Some(SnoopGuard::new(forwarder, &guard.rules, backend, executor)?);
+        }
+
+        Ok(guard)
     }
 }
 
 impl Drop for TProxyGuard {
     fn drop(&mut self) {
-        let output_chain_name = &self.output_chain_name;
-        let prerouting_chain_name = &self.prerouting_chain_name;
-
+        self.snoop_guard.take();
         std::thread::sleep(Duration::from_millis(100));
 
-        (cmd_lib::run_cmd! {
-            iptables -t mangle -D PREROUTING -j ${prerouting_chain_name};
-            iptables -t mangle -F ${prerouting_chain_name};
-            iptables -t mangle -X ${prerouting_chain_name};
-
-            iptables -t mangle -D OUTPUT -j ${output_chain_name};
-            iptables -t mangle -F ${output_chain_name};
-            iptables -t mangle -X ${output_chain_name};
-        })
-        .expect("drop iptables and cgroup failed");
-
-        if self.override_dns.is_some() {
-            (cmd_lib::run_cmd! {
-            iptables -t nat -D OUTPUT -j ${output_chain_name};
-            iptables -t nat -F ${output_chain_name};
-            iptables -t nat -X ${output_chain_name};
-            })
-            .expect("drop iptables failed");
+        self.cgroup_guard.uninstall(self.backend, &self.rules);
+        if !self.iprule_guard.remove() {
+            self.cgroup_guard.journal.keep();
         }
     }
 }
 
 #[allow(unused)]
 pub struct TraceGuard {
-    prerouting_chain_name: String,
-    output_chain_name: String,
+    rules: RuleSet,
+    backend: Backend,
     cgroup_guard: CGroupGuard,
 }
 
 impl TraceGuard {
     pub fn new(
         output_chain_name: &str,
-        prerouting_chain_name: &str,
-        cgroup_guard: CGroupGuard,
+        _prerouting_chain_name: &str,
+        mut cgroup_guard: CGroupGuard,
+        backend: Backend,
+        options: &SessionOptions,
     ) -> Result<Self> {
-        let class_id = cgroup_guard.class_id;
-        (cmd_lib::run_cmd! {
-        // iptables -t raw -N ${prerouting_chain_name};
-        // iptables -t raw -A PREROUTING -j ${prerouting_chain_name};
-        // iptables -t raw -A ${prerouting_chain_name} -p udp -j LOG;
-        // iptables -t raw -A ${prerouting_chain_name} -p tcp -j LOG;
-
-        iptables -t raw -N ${output_chain_name};
-        iptables -t raw -A OUTPUT -j ${output_chain_name};
-        iptables -t raw -A ${output_chain_name} -m cgroup --cgroup ${class_id} -p tcp -j LOG;
-        iptables -t raw -A ${output_chain_name} -m cgroup --cgroup ${class_id} -p udp -j LOG;
+        let rules = plan::trace_rules(output_chain_name, &cgroup_guard.spec, options);
+        cgroup_guard.journal.record(|s| {
+            s.backend = Some(backend);
+            s.rules = Some(rules.clone());
         })?;
+        cgroup_guard.executor.install(backend, &rules)?;
 
         Ok(Self {
-            output_chain_name: output_chain_name.to_owned(),
-            prerouting_chain_name: prerouting_chain_name.to_owned(),
+            rules,
+            backend,
             cgroup_guard,
         })
     }
@@ -360,20 +618,892 @@ impl TraceGuard {
 
 impl Drop for TraceGuard {
     fn drop(&mut self) {
-        let output_chain_name = &self.output_chain_name;
-        let _prerouting_chain_name = &self.prerouting_chain_name;
-
         std::thread::sleep(Duration::from_millis(100));
 
-        (cmd_lib::run_cmd! {
-            // iptables -t raw -D PREROUTING -j ${prerouting_chain_name};
-            // iptables -t raw -F ${prerouting_chain_name};
-            // iptables -t raw -X ${prerouting_chain_name};
+        self.cgroup_guard.uninstall(self.backend, &self.rules);
+    }
+}
+
+/// Denies the cgroup all network access, see [`plan::block_rules`].
+pub struct BlockGuard {
+    rules: RuleSet,
+    backend: Backend,
+    cgroup_guard: CGroupGuard,
+}
+
+impl BlockGuard {
+    pub fn new(
+        output_chain_name: &str,
+        mut cgroup_guard: CGroupGuard,
+        backend: Backend,
+        options: &SessionOptions,
+    ) -> Result<Self> {
+        let rules = plan::block_rules(output_chain_name, &cgroup_guard.spec, options);
+        cgroup_guard.journal.record(|s| {
+            s.backend = Some(backend);
+            s.rules = Some(rules.clone());
+        })?;
+        cgroup_guard.executor.install(backend, &rules)?;
+
+        Ok(Self {
+            rules,
+            backend,
+            cgroup_guard,
+        })
+    }
+}
+
+impl Drop for BlockGuard {
+    fn drop(&mut self) {
+        self.cgroup_guard.uninstall(self.backend, &self.rules);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{Op, Recorder};
+    use crate::rules::{CgroupMatch, Chain, Hook, Match, Protocol, Rule, Table, Target};
+
+    const ID: u32 = 42;
+    const PID: u32 = 1234;
+
+    fn spec(hier_v2: bool) -> CgroupSpec {
+        CgroupSpec {
+            path: format!("cproxy-{}", ID),
+            class_id: ID,
+            hier_v2,
+        }
+    }
+
+    fn cgroup_guard(recorder: &Arc<Recorder>, hier_v2: bool) -> CGroupGuard {
+        CGroupGuard::create(
+            spec(hier_v2),
+            Some(PID),
+            Journal::in_memory(ID),
+            recorder.clone(),
+        )
+        .unwrap()
+    }
 
-            iptables -t raw -D OUTPUT -j ${output_chain_name};
-            iptables -t raw -F ${output_chain_name};
-            iptables -t raw -X ${output_chain_name};
+    fn cgroup_match(hier_v2: bool) -> Match {
+        Match::Cgroup(if hier_v2 {
+            CgroupMatch::Path(format!("cproxy-{}", ID))
+        } else {
+            CgroupMatch::ClassId(ID)
         })
-        .expect("drop iptables and cgroup failed");
+    }
+
+    fn kill_switch(chain_name: &str, hier_v2: bool) -> Chain {
+        Chain::new(chain_name, Family::V6, Table::Filter, Hook::Output)
+            .rule(vec![Match::OutInterface("lo".into())], Target::Return)
+            .rule(vec![cgroup_match(hier_v2)], Target::Reject)
+    }
+
+    fn route(family: Family) -> PolicyRoute {
+        PolicyRoute {
+            family,
+            mark: Mark::new(ID),
+            table: ID,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn redirect_rules_and_teardown_order() {
+        for hier_v2 in [false, true] {
+            let recorder = Recorder::new();
+            let options = SessionOptions {
+                redirect_dns: true,
+                block_ipv6: true,
+                ..Default::default()
+            };
+            let guard = RedirectGuard::new(
+                1080,
+                "cp_rd_out_42",
+                cgroup_guard(&recorder, hier_v2),
+                Backend::Iptables,
+                &options,
+            )
+            .unwrap();
+            drop(guard);
+
+            let cgroup = cgroup_match(hier_v2);
+            let rules = RuleSet::new("cproxy_42")
+                .chain(
+                    Chain::new("cp_rd_out_42", Family::V4, Table::Nat, Hook::Output)
+                        .rule(
+                            vec![
+                                Match::Protocol(Protocol::Udp),
+                                cgroup.clone(),
+                                Match::DstPort(53),
+                            ],
+                            Target::Redirect { port: 1080 },
+                        )
+                        .rule(
+                            vec![
+                                Match::Protocol(Protocol::Tcp),
+                                cgroup.clone(),
+                                Match::DstPort(53),
+                            ],
+                            Target::Redirect { port: 1080 },
+                        )
+                        .rule(
+                            vec![
+                                Match::Protocol(Protocol::Udp),
+                                Match::OutInterface("lo".into()),
+                            ],
+                            Target::Return,
+                        )
+                        .rule(
+                            vec![
+                                Match::Protocol(Protocol::Tcp),
+                                Match::OutInterface("lo".into()),
+                            ],
+                            Target::Return,
+                        )
+                        .rule(
+                            vec![Match::Protocol(Protocol::Tcp), cgroup],
+                            Target::Redirect { port: 1080 },
+                        ),
+                )
+                .chain(kill_switch("cp_rd_out_42", hier_v2));
+            assert_eq!(
+                recorder.ops(),
+                vec![
+                    Op::CreateCgroup(spec(hier_v2)),
+                    Op::AddTask(spec(hier_v2).path, PID),
+                    Op::Install(Backend::Iptables, rules.clone()),
+                    Op::Uninstall(Backend::Iptables, rules),
+                    Op::DeleteCgroup(spec(hier_v2).path),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn bypass_returns_before_proxying() {
+        let nets = ["10.0.0.0/8", "10.1.0.0/16", "192.168.1.1", "fc00::/7"];
+        let options = SessionOptions {
+            block_ipv6: true,
+            bypass: nets.iter().map(|n| n.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = RedirectGuard::new(
+            1080,
+            "cp_rd_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let bypass =
+            |nets: &[&str]| Match::DstNet(nets.iter().map(|n| n.parse().unwrap()).collect());
+        let chains = match &recorder.ops()[2] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        assert_eq!(
+            chains[0].rules[2],
+            Rule::new(
+                vec![bypass(&["10.0.0.0/8", "192.168.1.1/32"])],
+                Target::Return
+            )
+        );
+        assert_eq!(chains[0].rules[3].target, Target::Redirect { port: 1080 });
+        assert_eq!(
+            chains[1].rules[1],
+            Rule::new(vec![bypass(&["fc00::/7"])], Target::Return)
+        );
+    }
+
+    #[test]
+    fn udp_policy_refuses_udp_redirect_mode_cannot_proxy() {
+        let options = SessionOptions {
+            bypass: vec!["10.0.0.0/8".parse().unwrap()],
+            only_dport: vec!["443".parse().unwrap()],
+            udp_policy: UdpPolicy::Reject,
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = RedirectGuard::new(
+            1080,
+            "cp_rd_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chains = match &recorder.ops()[2] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let udp = Match::Protocol(Protocol::Udp);
+        assert_eq!(
+            chains[1],
+            Chain::new("cp_rd_out_42", Family::V4, Table::Filter, Hook::Output)
+                .rule(
+                    vec![udp.clone(), Match::OutInterface("lo".into())],
+                    Target::Return
+                )
+                .rule(
+                    vec![udp.clone(), cgroup_match(true), Match::DstPort(53)],
+                    Target::Return
+                )
+                .rule(
+                    vec![Match::DstNet(vec!["10.0.0.0/8".parse().unwrap()])],
+                    Target::Return
+                )
+                .rule(
+                    vec![
+                        udp,
+                        cgroup_match(true),
+                        Match::DstPorts(vec![PortRange {
+                            start: 443,
+                            end: 443
+                        }]),
+                    ],
+                    Target::Reject
+                )
+        );
+    }
+
+    #[test]
+    fn override_dns_spreads_over_servers_of_each_family() {
+        let servers: Vec<Destination> = ["10.0.0.1:5353", "10.0.0.2", "[2001:db8::1]:53"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let options = SessionOptions {
+            ipv6: true,
+            override_dns: servers.clone(),
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = RedirectGuard::new(
+            1080,
+            "cp_rd_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Iptables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chains = match &recorder.ops()[2] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let dns = |proto| {
+            vec![
+                Match::Protocol(proto),
+                cgroup_match(true),
+                Match::DstPort(53),
+            ]
+        };
+        let mut expected = Vec::new();
+        for proto in [Protocol::Udp, Protocol::Tcp] {
+            let mut first = dns(proto);
+            first.push(Match::Nth {
+                every: 2,
+                packet: 0,
+            });
+            expected.push(Rule::new(first, Target::Dnat(servers[0])));
+            expected.push(Rule::new(dns(proto), Target::Dnat(servers[1])));
+        }
+        assert_eq!(chains[0].rules[2..6], expected[..]);
+        assert_eq!(
+            chains[1].rules[2],
+            Rule::new(dns(Protocol::Udp), Target::Dnat(servers[2]))
+        );
+    }
+
+    #[test]
+    fn bypass_files_go_into_sets() {
+        let options = SessionOptions {
+            block_ipv6: true,
+            bypass: vec!["10.0.0.0/8".parse().unwrap()],
+            bypass_files: vec!["1.0.1.0/24".parse().unwrap(), "240e::/20".parse().unwrap()],
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = RedirectGuard::new(
+            1080,
+            "cp_rd_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Iptables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let rules = match &recorder.ops()[2] {
+            Op::Install(_, rules) => rules.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let sets: Vec<_> = rules
+            .sets
+            .iter()
+            .map(|s| (s.name.as_str(), s.nets.len()))
+            .collect();
+        assert_eq!(sets, [("cp_bypass_42", 2), ("cp_bypass6_42", 1)]);
+        assert_eq!(
+            rules.chains[0].rules[2],
+            Rule::new(vec![Match::DstSet("cp_bypass_42".into())], Target::Return)
+        );
+        assert_eq!(
+            rules.chains[1].rules[1],
+            Rule::new(vec![Match::DstSet("cp_bypass6_42".into())], Target::Return)
+        );
+        assert_eq!(recorder.ops()[3], Op::Uninstall(Backend::Iptables, rules));
+    }
+
+    #[test]
+    fn proxy_domains_learn_addresses_through_the_forwarder() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = SessionOptions {
+            override_dns: vec![upstream.local_addr().unwrap().to_string().parse().unwrap()],
+            proxy_domains: vec!["example.com".parse().unwrap()],
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = RedirectGuard::new(
+            1080,
+            "cp_rd_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+
+        let rules = match &recorder.ops()[2] {
+            Op::Install(_, rules) => rules.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        assert_eq!(rules.sets.len(), 1);
+        assert!(rules.sets[0].timeout);
+        let forwarder_port = match &rules.chains[0].rules[1] {
+            Rule {
+                target: Target::Redirect { port },
+                ..
+            } => *port as u16,
+            rule => panic!("unexpected {:?}", rule),
+        };
+        assert_eq!(
+            rules.chains[0].rules[0],
+            Rule::new(vec![Match::Mark(Mark::new(dns::MARK))], Target::Return)
+        );
+        assert_eq!(
+            rules.chains[0].rules.last().unwrap().matches[2],
+            Match::DstSet("cp_domains_42".into())
+        );
+
+        // Marking the forwarder's queries needs CAP_NET_ADMIN.
+        if nix::unistd::geteuid().is_root() {
+            let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let query = [0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            client
+                .send_to(&query, ("127.0.0.1", forwarder_port))
+                .unwrap();
+            let mut buf = [0; 512];
+            let (_, from) = upstream.recv_from(&mut buf).unwrap();
+            let mut answer = vec![0, 7, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+            answer.extend(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+            answer.extend(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\xc0\x00\x02\x01");
+            upstream.send_to(&answer, from).unwrap();
+            client.recv(&mut buf).unwrap();
+            assert_eq!(
+                recorder.ops()[3],
+                Op::AddToSet("cp_domains_42".into(), "192.0.2.1".parse().unwrap(), 3600)
+            );
+        }
+        drop(guard);
+        assert_eq!(
+            recorder.ops().last(),
+            Some(&Op::DeleteCgroup("cproxy-42".into()))
+        );
+    }
+
+    #[test]
+    fn only_dst_and_dport_narrow_the_marked_traffic() {
+        let options = SessionOptions {
+            ipv6: true,
+            only_dst: vec!["203.0.113.0/24".parse().unwrap()],
+            only_dport: ["8000-8080", "443", "8080:8443"]
+                .iter()
+                .map(|p| p.parse().unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = TProxyGuard::new(
+            1080,
+            Mark::new(ID),
+            "cp_tp_out_42",
+            "cp_tp_pre_42",
+            cgroup_guard(&recorder, true),
+            Backend::Iptables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chains = match &recorder.ops()[6] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let only = vec![
+            Match::DstNet(vec!["203.0.113.0/24".parse().unwrap()]),
+            Match::DstPorts(vec![
+                PortRange {
+                    start: 443,
+                    end: 443,
+                },
+                PortRange {
+                    start: 8000,
+                    end: 8443,
+                },
+            ]),
+        ];
+        let marked: Vec<_> = chains[1]
+            .rules
+            .iter()
+            .filter(|r| r.target == Target::SetMark(Mark::new(ID)))
+            .map(|r| r.matches[2..].to_vec())
+            .collect();
+        assert_eq!(marked, vec![only.clone(), only]);
+        // No IPv6 range, so nothing of IPv6 is marked.
+        assert_eq!(chains[3].family, Family::V6);
+        assert!(chains[3].rules.iter().all(|r| r.target == Target::Return));
+    }
+
+    #[test]
+    fn port_map_picks_the_tproxy_port() {
+        let options = SessionOptions {
+            udp_port: Some(1081),
+            dns_port: Some(5353),
+            port_map: vec!["80,443=8118".parse().unwrap()],
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = TProxyGuard::new(
+            1080,
+            Mark::new(ID),
+            "cp_tp_out_42",
+            "cp_tp_pre_42",
+            cgroup_guard(&recorder, true),
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chains = match &recorder.ops()[4] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let ports: Vec<_> = chains[0]
+            .rules
+            .iter()
+            .map(|r| match (&r.matches[0], &r.target) {
+                (Match::Protocol(proto), Target::TProxy { port, .. }) => (*proto, *port),
+                rule => panic!("unexpected {:?}", rule),
+            })
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                (Protocol::Udp, 5353),
+                (Protocol::Tcp, 5353),
+                (Protocol::Udp, 8118),
+                (Protocol::Tcp, 8118),
+                (Protocol::Udp, 1081),
+                (Protocol::Tcp, 1080),
+            ]
+        );
+        assert_eq!(chains[0].rules[0].matches[2], Match::DstPort(53));
+        assert_eq!(chains[0].rules[1].matches[2], Match::DstPort(53));
+    }
+
+    #[test]
+    fn override_dns_is_not_taken_by_tproxy() {
+        let server: Destination = "1.1.1.1:53".parse().unwrap();
+        let options = SessionOptions {
+            override_dns: vec![server],
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = TProxyGuard::new(
+            1080,
+            Mark::new(ID),
+            "cp_tp_out_42",
+            "cp_tp_pre_42",
+            cgroup_guard(&recorder, true),
+            Backend::Iptables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chains = match &recorder.ops()[4] {
+            Op::Install(_, rules) => rules.chains.clone(),
+            op => panic!("unexpected {:?}", op),
+        };
+        let dns = |proto| {
+            vec![
+                Match::Protocol(proto),
+                cgroup_match(true),
+                Match::DstPort(53),
+            ]
+        };
+        let protos = [Protocol::Udp, Protocol::Tcp];
+        let unmarked: Vec<_> = protos
+            .iter()
+            .map(|&p| Rule::new(dns(p), Target::Return))
+            .collect();
+        assert_eq!(chains[1].rules[..2], unmarked[..]);
+        let dnat: Vec<_> = protos
+            .iter()
+            .map(|&p| Rule::new(dns(p), Target::Dnat(server)))
+            .collect();
+        assert_eq!(
+            (chains[2].table, &chains[2].rules[2..]),
+            (Table::Nat, &dnat[..])
+        );
+    }
+
+    #[test]
+    fn tproxy_rules_and_teardown_order() {
+        for hier_v2 in [false, true] {
+            let recorder = Recorder::new();
+            let options = SessionOptions {
+                ipv6: true,
+                ..Default::default()
+            };
+            let guard = TProxyGuard::new(
+                1080,
+                Mark::new(ID),
+                "cp_tp_out_42",
+                "cp_tp_pre_42",
+                cgroup_guard(&recorder, hier_v2),
+                Backend::Nftables,
+                &options,
+            )
+            .unwrap();
+            drop(guard);
+
+            let mut rules = RuleSet::new("cproxy_42");
+            for family in [Family::V4, Family::V6] {
+                let mut prerouting =
+                    Chain::new("cp_tp_pre_42", family, Table::Mangle, Hook::Prerouting);
+                for proto in [Protocol::Udp, Protocol::Tcp] {
+                    prerouting = prerouting.rule(
+                        vec![Match::Protocol(proto), Match::Mark(Mark::new(ID))],
+                        Target::TProxy {
+                            ip: family.localhost(),
+                            port: 1080,
+                        },
+                    );
+                }
+                let mut output = Chain::new("cp_tp_out_42", family, Table::Mangle, Hook::Output);
+                for proto in [Protocol::Tcp, Protocol::Udp] {
+                    output = output.rule(
+                        vec![Match::Protocol(proto), Match::OutInterface("lo".into())],
+                        Target::Return,
+                    );
+                }
+                for proto in [Protocol::Tcp, Protocol::Udp] {
+                    output = output.rule(
+                        vec![Match::Protocol(proto), cgroup_match(hier_v2)],
+                        Target::SetMark(Mark::new(ID)),
+                    );
+                }
+                rules = rules.chain(prerouting).chain(output);
+            }
+            let (v4, v6) = (route(Family::V4), route(Family::V6));
+            assert_eq!(
+                recorder.ops(),
+                vec![
+                    Op::CreateCgroup(spec(hier_v2)),
+                    Op::AddTask(spec(hier_v2).path, PID),
+                    Op::AddRule(v4),
+                    Op::AddRoute(v4),
+                    Op::AddRule(v6),
+                    Op::AddRoute(v6),
+                    Op::Install(Backend::Nftables, rules.clone()),
+                    Op::Uninstall(Backend::Nftables, rules),
+                    Op::DeleteRule(v4),
+                    Op::DeleteRoute(v4),
+                    Op::DeleteRule(v6),
+                    Op::DeleteRoute(v6),
+                    Op::DeleteCgroup(spec(hier_v2).path),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn failed_teardown_goes_on_and_keeps_journal() {
+        let options = SessionOptions::default();
+        let rules = plan::tproxy_rules(
+            1080,
+            Mark::new(ID),
+            "cp_tp_out_42",
+            "cp_tp_pre_42",
+            &spec(true),
+            &options,
+        );
+        let v4 = route(Family::V4);
+        let recorder = Recorder::new();
+        recorder.fail(Op::Uninstall(Backend::Nftables, rules));
+        recorder.fail(Op::DeleteRule(v4));
+        let path = std::env::temp_dir().join(format!("cproxy-test-{}.json", std::process::id()));
+        let cgroup_guard = CGroupGuard::create(
+            spec(true),
+            Some(PID),
+            Journal::create_at(path.clone(), ID).unwrap().unwrap(),
+            recorder.clone(),
+        )
+        .unwrap();
+        let guard = TProxyGuard::new(
+            1080,
+            Mark::new(ID),
+            "cp_tp_out_42",
+            "cp_tp_pre_42",
+            cgroup_guard,
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        assert_eq!(
+            recorder.ops()[5..],
+            [Op::DeleteRoute(v4), Op::DeleteCgroup(spec(true).path)]
+        );
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trace_rules_and_teardown_order() {
+        for hier_v2 in [false, true] {
+            let recorder = Recorder::new();
+            let guard = TraceGuard::new(
+                "cp_tr_out_42",
+                "cp_tr_pre_42",
+                cgroup_guard(&recorder, hier_v2),
+                Backend::Iptables,
+                &SessionOptions::default(),
+            )
+            .unwrap();
+            drop(guard);
+
+            let rules = RuleSet::new("cproxy_42").chain(
+                Chain::new("cp_tr_out_42", Family::V4, Table::Raw, Hook::Output)
+                    .rule(
+                        vec![cgroup_match(hier_v2), Match::Protocol(Protocol::Tcp)],
+                        Target::Log,
+                    )
+                    .rule(
+                        vec![cgroup_match(hier_v2), Match::Protocol(Protocol::Udp)],
+                        Target::Log,
+                    ),
+            );
+            assert_eq!(
+                recorder.ops(),
+                vec![
+                    Op::CreateCgroup(spec(hier_v2)),
+                    Op::AddTask(spec(hier_v2).path, PID),
+                    Op::Install(Backend::Iptables, rules.clone()),
+                    Op::Uninstall(Backend::Iptables, rules),
+                    Op::DeleteCgroup(spec(hier_v2).path),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn block_rejects_all_but_allowed_destinations() {
+        let options = SessionOptions {
+            allow_loopback: true,
+            allow_dst: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            log_blocked: true,
+            ..Default::default()
+        };
+        let recorder = Recorder::new();
+        let guard = BlockGuard::new(
+            "cp_bl_out_42",
+            cgroup_guard(&recorder, true),
+            Backend::Nftables,
+            &options,
+        )
+        .unwrap();
+        drop(guard);
+
+        let chain = |family, allowed: &str| {
+            Chain::new("cp_bl_out_42", family, Table::Filter, Hook::Output)
+                .rule(vec![Match::OutInterface("lo".into())], Target::Return)
+                .rule(
+                    vec![Match::DstNet(vec![allowed.parse().unwrap()])],
+                    Target::Return,
+                )
+                .rule(vec![cgroup_match(true)], Target::Log)
+                .rule(vec![cgroup_match(true)], Target::Reject)
+        };
+        let rules = RuleSet::new("cproxy_42")
+            .chain(chain(Family::V4, "10.0.0.0/8"))
+            .chain(chain(Family::V6, "2001:db8::/32"));
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::CreateCgroup(spec(true)),
+                Op::AddTask(spec(true).path, PID),
+                Op::Install(Backend::Nftables, rules.clone()),
+                Op::Uninstall(Backend::Nftables, rules),
+                Op::DeleteCgroup(spec(true).path),
+            ]
+        );
+    }
+
+    #[test]
+    fn bpf_redirect_detaches_before_deleting_cgroup() {
+        let recorder = Recorder::new();
+        let options = SessionOptions {
+            block_ipv6: true,
+            ..Default::default()
+        };
+        let guard = BpfRedirectGuard::new(1080, cgroup_guard(&recorder, true), &options).unwrap();
+        drop(guard);
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::CreateCgroup(spec(true)),
+                Op::AddTask(spec(true).path, PID),
+                Op::AttachBpf {
+                    path: spec(true).path,
+                    port: 1080,
+                    options: Box::new(options),
+                },
+                Op::DetachBpf(spec(true).path),
+                Op::DeleteCgroup(spec(true).path),
+            ]
+        );
+    }
+
+    #[test]
+    fn bpf_redirect_needs_cgroup_v2() {
+        let recorder = Recorder::new();
+        let result =
+            BpfRedirectGuard::new(1080, cgroup_guard(&recorder, false), &Default::default());
+        assert!(result.is_err());
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::CreateCgroup(spec(false)),
+                Op::AddTask(spec(false).path, PID),
+                Op::DeleteCgroup(spec(false).path),
+            ]
+        );
+    }
+
+    #[test]
+    fn cgroup_is_deleted_if_task_cannot_be_added() {
+        let recorder = Recorder::new();
+        recorder.fail(Op::AddTask(spec(true).path, PID));
+        let result = CGroupGuard::create(
+            spec(true),
+            Some(PID),
+            Journal::in_memory(ID),
+            recorder.clone(),
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::CreateCgroup(spec(true)),
+                Op::DeleteCgroup(spec(true).path),
+            ]
+        );
+    }
+
+    #[test]
+    fn ip_rules_are_rolled_back_on_failure() {
+        let recorder = Recorder::new();
+        let (v4, v6) = (route(Family::V4), route(Family::V6));
+        recorder.fail(Op::AddRoute(v6));
+        let mut journal = Journal::in_memory(ID);
+        let result = IpRuleGuard::new(
+            Mark::new(ID),
+            ID,
+            None,
+            &[Family::V4, Family::V6],
+            &mut journal,
+            recorder.clone(),
+        );
+        assert!(result.is_err());
+        // Recorded before anything is added, so `cproxy cleanup` could find it after a crash.
+        assert_eq!(journal.session().routes, vec![v4, v6]);
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::AddRule(v4),
+                Op::AddRoute(v4),
+                Op::AddRule(v6),
+                Op::DeleteRule(v6),
+                Op::DeleteRoute(v4),
+                Op::DeleteRule(v4),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_ip_rule_is_added_again() {
+        let recorder = Recorder::new();
+        let v4 = route(Family::V4);
+        let guard = IpRuleGuard::new(
+            Mark::new(ID),
+            ID,
+            None,
+            &[Family::V4],
+            &mut Journal::in_memory(ID),
+            recorder.clone(),
+        )
+        .unwrap();
+        recorder.remove(Removed::Rule, v4);
+        // The monitor fails, the watcher goes on after looking for what is missing.
+        recorder.remove_unnoticed(Removed::Route, v4);
+        recorder.remove(Removed::Rule, v4);
+        for _ in 0..100 {
+            if recorder.ops().len() > 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(guard);
+        assert_eq!(
+            recorder.ops(),
+            vec![
+                Op::AddRule(v4),
+                Op::AddRoute(v4),
+                Op::AddRule(v4),
+                Op::AddRoute(v4),
+                Op::AddRule(v4),
+                Op::DeleteRule(v4),
+                Op::DeleteRoute(v4),
+            ]
+        );
     }
 }
@@ -0,0 +1,339 @@
+//! Range lists for `--bypass-file` and `--only-dst-file`. Plain text files have one range per
+//! line. A v2ray `geoip.dat` or a MaxMind GeoLite2 blocks CSV is given with a country after a
+//! colon, like `geoip.dat:cn` or `GeoLite2-Country-Blocks-IPv4.csv:cn`.
+
+use crate::rules::Cidr;
+use eyre::{eyre, Result, WrapErr};
+use std::path::Path;
+
+/// Loads the ranges of one `--bypass-file` or `--only-dst-file` argument.
+pub fn load(spec: &str) -> Result<Vec<Cidr>> {
+    match spec.rsplit_once(':') {
+        Some((path, code)) if path.ends_with(".dat") => geoip(Path::new(path), code),
+        Some((path, country)) if path.ends_with(".csv") => geolite2(Path::new(path), country),
+        _ if spec.ends_with(".dat") => {
+            Err(eyre!("{} needs a country code, like `{}:cn`", spec, spec))
+        }
+        _ => text(Path::new(spec)),
+    }
+}
+
+/// One range per line, `#` starts a comment. Only the first column of CSV lines is used.
+fn text(path: &Path) -> Result<Vec<Cidr>> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let mut nets = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let field = line.split(',').next().unwrap_or_default().trim();
+        if i == 0 && field == "network" {
+            // Has a row for every network in the world.
+            return Err(eyre!(
+                "{} is a GeoLite2 blocks file and needs a country, like `{}:cn`",
+                path.display(),
+                path.display()
+            ));
+        }
+        if field.is_empty() {
+            continue;
+        }
+        let net = field
+            .parse()
+            .wrap_err_with(|| format!("{}:{}", path.display(), i + 1))?;
+        nets.push(net);
+    }
+    Ok(nets)
+}
+
+/// Header and rows of a CSV file. None of the columns read here are quoted or contain commas.
+fn csv(path: &Path) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let split = |line: &str| line.split(',').map(|f| f.trim().to_owned()).collect();
+    let header = lines.next().map(split).unwrap_or_default();
+    Ok((header, lines.map(split).collect()))
+}
+
+fn column(path: &Path, header: &[String], name: &str) -> Result<usize> {
+    header
+        .iter()
+        .position(|c| c == name)
+        .ok_or_else(|| eyre!("{} has no `{}` column", path.display(), name))
+}
+
+/// The GeoLite2 locations file next to a blocks file, the English one if there are several.
+fn locations_file(blocks: &Path) -> Result<std::path::PathBuf> {
+    let dir = blocks.parent().unwrap_or_else(|| Path::new("."));
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .wrap_err_with(|| format!("failed to read {}", dir.display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.contains("-Locations-") && name.ends_with(".csv")
+        })
+        .collect();
+    files.sort_by_key(|path| !path.to_string_lossy().ends_with("-en.csv"));
+    files.into_iter().next().ok_or_else(|| {
+        eyre!(
+            "no GeoLite2 locations CSV next to {}, give a geoname id instead of the country code",
+            blocks.display()
+        )
+    })
+}
+
+/// Geoname ids of `country`, an ISO code looked up in the locations file or a geoname id.
+fn geoname_ids(blocks: &Path, country: &str) -> Result<Vec<String>> {
+    if country.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(vec![country.to_owned()]);
+    }
+    let path = locations_file(blocks)?;
+    let (header, rows) = csv(&path)?;
+    let (id, code) = (
+        column(&path, &header, "geoname_id")?,
+        column(&path, &header, "country_iso_code")?,
+    );
+    let ids: Vec<String> = rows
+        .into_iter()
+        .filter(|row| {
+            row.get(code)
+                .is_some_and(|c| c.eq_ignore_ascii_case(country))
+        })
+        .filter_map(|row| row.get(id).cloned())
+        .collect();
+    if ids.is_empty() {
+        return Err(eyre!("no `{}` in {}", country, path.display()));
+    }
+    Ok(ids)
+}
+
+/// Networks of a GeoLite2 blocks file located in `country`, or registered there if the file
+/// doesn't know where they are.
+fn geolite2(path: &Path, country: &str) -> Result<Vec<Cidr>> {
+    let ids = geoname_ids(path, country)?;
+    let (header, rows) = csv(path)?;
+    let network = column(path, &header, "network")?;
+    let located = column(path, &header, "geoname_id")?;
+    let registered = column(path, &header, "registered_country_geoname_id")?;
+    let mut nets = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let field = |column: usize| row.get(column).map(String::as_str).unwrap_or_default();
+        let id = match field(located) {
+            "" => field(registered),
+            id => id,
+        };
+        if ids.iter().any(|i| i == id) {
+            let net = field(network)
+                .parse()
+                .wrap_err_with(|| format!("{}:{}", path.display(), i + 2))?;
+            nets.push(net);
+        }
+    }
+    Ok(nets)
+}
+
+fn geoip(path: &Path, code: &str) -> Result<Vec<Cidr>> {
+    let content =
+        std::fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    parse_geoip(&content, code).wrap_err_with(|| format!("invalid {}", path.display()))
+}
+
+/// Value of a protobuf field.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Just enough protobuf to read `geoip.dat`.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first().ok_or_else(|| eyre!("truncated"))?;
+            self.0 = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(eyre!("varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(eyre!("truncated"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Bytes(self.take(8)?),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Bytes(self.take(4)?),
+            wire_type => return Err(eyre!("unsupported wire type {}", wire_type)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// `GeoIPList { repeated GeoIP entry = 1; }`
+/// `GeoIP { string country_code = 1; repeated CIDR cidr = 2; bool reverse_match = 3; }`
+/// `CIDR { bytes ip = 1; uint32 prefix = 2; }`
+fn parse_geoip(content: &[u8], code: &str) -> Result<Vec<Cidr>> {
+    let mut list = Reader(content);
+    while let Some((field, value)) = list.field()? {
+        let entry = match (field, value) {
+            (1, Value::Bytes(entry)) => entry,
+            _ => continue,
+        };
+        let mut entry = Reader(entry);
+        let mut country = None;
+        let mut cidrs = Vec::new();
+        let mut reverse = false;
+        while let Some((field, value)) = entry.field()? {
+            match (field, value) {
+                (1, Value::Bytes(bytes)) => country = Some(String::from_utf8_lossy(bytes)),
+                (2, Value::Bytes(bytes)) => cidrs.push(bytes),
+                (3, Value::Varint(value)) => reverse = value != 0,
+                _ => {}
+            }
+        }
+        if !country.is_some_and(|c| c.eq_ignore_ascii_case(code)) {
+            continue;
+        }
+        if reverse {
+            return Err(eyre!("reverse matching `{}` is not supported", code));
+        }
+        return cidrs.into_iter().map(parse_cidr).collect();
+    }
+    Err(eyre!("no `{}` entry", code))
+}
+
+fn parse_cidr(bytes: &[u8]) -> Result<Cidr> {
+    let mut cidr = Reader(bytes);
+    let (mut ip, mut prefix) = (None, 0);
+    while let Some((field, value)) = cidr.field()? {
+        match (field, value) {
+            (1, Value::Bytes(bytes)) => ip = Some(bytes),
+            (2, Value::Varint(value)) => prefix = value,
+            _ => {}
+        }
+    }
+    let ip = match ip {
+        Some(&[a, b, c, d]) => std::net::Ipv4Addr::new(a, b, c, d).to_string(),
+        Some(ip) if ip.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(ip);
+            std::net::Ipv6Addr::from(octets).to_string()
+        }
+        _ => return Err(eyre!("invalid address in range")),
+    };
+    format!("{}/{}", ip, prefix).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(field: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![field << 3 | 2, value.len() as u8];
+        encoded.extend(value);
+        encoded
+    }
+
+    fn geoip_entry(code: &str, cidrs: &[(&[u8], u8)]) -> Vec<u8> {
+        let mut entry = bytes(1, code.as_bytes());
+        for (ip, prefix) in cidrs {
+            let mut cidr = bytes(1, ip);
+            cidr.extend([2 << 3, *prefix]);
+            entry.extend(bytes(2, &cidr));
+        }
+        bytes(1, &entry)
+    }
+
+    #[test]
+    fn reads_one_country_of_geoip_dat() {
+        let mut content = geoip_entry("US", &[(&[8, 8, 8, 0], 24)]);
+        content.extend(geoip_entry(
+            "CN",
+            &[
+                (&[1, 0, 1, 0], 24),
+                (&[0x24, 0x0e, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 20),
+            ],
+        ));
+        let nets: Vec<String> = parse_geoip(&content, "cn")
+            .unwrap()
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        assert_eq!(nets, ["1.0.1.0/24", "240e::/20"]);
+        assert!(parse_geoip(&content, "jp").is_err());
+    }
+
+    #[test]
+    fn reads_text_and_one_country_of_maxmind_csv() {
+        let dir = std::env::temp_dir().join(format!("cproxy-iplist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("list.txt");
+        std::fs::write(&text, "# cloud\n203.0.113.0/24\n\n2001:db8::1 # lab\n").unwrap();
+        let csv = dir.join("GeoLite2-Country-Blocks-IPv4.csv");
+        std::fs::write(
+            &csv,
+            "network,geoname_id,registered_country_geoname_id\n\
+             1.0.0.0/24,2077456,2077456\n\
+             1.0.1.0/24,1814991,1814991\n\
+             1.0.2.0/23,,1814991\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("GeoLite2-Country-Locations-en.csv"),
+            "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name\n\
+             1814991,en,AS,Asia,CN,China\n\
+             2077456,en,OC,Oceania,AU,Australia\n",
+        )
+        .unwrap();
+        let csv = csv.to_str().unwrap();
+
+        let nets = load(text.to_str().unwrap()).unwrap();
+        let cn = load(&format!("{}:cn", csv)).unwrap();
+        let au = load(&format!("{}:2077456", csv)).unwrap();
+        let everything = load(csv);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let nets: Vec<String> = nets
+            .iter()
+            .chain(&cn)
+            .chain(&au)
+            .map(|n| n.to_string())
+            .collect();
+        assert_eq!(
+            nets,
+            [
+                "203.0.113.0/24",
+                "2001:db8::1/128",
+                "1.0.1.0/24",
+                "1.0.2.0/23",
+                "1.0.0.0/24"
+            ]
+        );
+        assert!(everything.is_err());
+    }
+}
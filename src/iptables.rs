@@ -0,0 +1,409 @@
+use crate::backend::pipe_to;
+use crate::rules::{
+    CgroupMatch, Chain, Counters, Family, Hook, Match, PortRange, Rule, RuleSet, Table, Target,
+};
+use eyre::Result;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+
+/// Timeout of entries of ipsets with [`IpSet::timeout`](crate::rules::IpSet) in seconds, every
+/// entry gets its own anyway.
+const DEFAULT_TIMEOUT: u32 = 300;
+
+/// Port specs one `-m multiport --dports` takes.
+pub const MULTIPORT_LIMIT: usize = 15;
+
+/// Port specs `ports` take up in a multiport match, a range counts as two.
+pub fn multiport_specs(ports: &[PortRange]) -> usize {
+    ports
+        .iter()
+        .map(|p| if p.start == p.end { 1 } else { 2 })
+        .sum()
+}
+
+fn rule_args(rule: &Rule) -> Vec<String> {
+    let mut args = Vec::new();
+    for m in &rule.matches {
+        match m {
+            Match::Protocol(proto) => args.extend(["-p".to_owned(), proto.to_string()]),
+            Match::OutInterface(iface) => args.extend(["-o".to_owned(), iface.clone()]),
+            Match::Cgroup(CgroupMatch::Path(path)) => {
+                args.extend(["-m", "cgroup", "--path"].map(String::from));
+                args.push(path.clone());
+            }
+            Match::Cgroup(CgroupMatch::ClassId(class_id)) => {
+                args.extend(["-m", "cgroup", "--cgroup"].map(String::from));
+                args.push(class_id.to_string());
+            }
+            Match::Mark(mark) => {
+                args.extend(["-m", "mark", "--mark"].map(String::from));
+                args.push(mark.to_string());
+            }
+            Match::DstPort(port) => args.extend(["--dport".to_owned(), port.to_string()]),
+            Match::DstNet(nets) => {
+                // iptables expands a list of destinations into one rule per range.
+                let nets: Vec<String> = nets.iter().map(|n| n.to_string()).collect();
+                args.extend(["-d".to_owned(), nets.join(",")]);
+            }
+            Match::DstPorts(ports) => {
+                let ports: Vec<String> = ports
+                    .iter()
+                    .map(|p| p.to_string().replace('-', ":"))
+                    .collect();
+                args.extend(["-m", "multiport", "--dports"].map(String::from));
+                args.push(ports.join(","));
+            }
+            Match::DstSet(name) => {
+                args.extend(["-m", "set", "--match-set"].map(String::from));
+                args.extend([name.clone(), "dst".to_owned()]);
+            }
+            Match::Nth { every, packet } => {
+                args.extend(["-m", "statistic", "--mode", "nth", "--every"].map(String::from));
+                args.extend([every.to_string(), "--packet".to_owned(), packet.to_string()]);
+            }
+        }
+    }
+    match &rule.target {
+        Target::Return => args.extend(["-j", "RETURN"].map(String::from)),
+        Target::Redirect { port } => {
+            args.extend(["-j", "REDIRECT", "--to-ports"].map(String::from));
+            args.push(port.to_string());
+        }
+        Target::TProxy { ip, port } => {
+            args.extend(["-j", "TPROXY", "--on-ip"].map(String::from));
+            args.push(ip.to_string());
+            args.push("--on-port".to_owned());
+            args.push(port.to_string());
+        }
+        Target::SetMark(mark) if mark.mask == u32::MAX => {
+            args.extend(["-j", "MARK", "--set-mark"].map(String::from));
+            args.push(mark.value.to_string());
+        }
+        Target::SetMark(mark) => {
+            args.extend(["-j", "MARK", "--set-xmark"].map(String::from));
+            args.push(mark.to_string());
+        }
+        Target::Dnat(destination) => {
+            args.extend(["-j", "DNAT", "--to-destination"].map(String::from));
+            args.push(destination.to_string());
+        }
+        Target::Log => args.extend(["-j", "LOG"].map(String::from)),
+        Target::Reject => args.extend(["-j", "REJECT"].map(String::from)),
+        Target::Drop => args.extend(["-j", "DROP"].map(String::from)),
+    }
+    args
+}
+
+fn quote(arg: &str) -> String {
+    if arg.contains(char::is_whitespace) || arg.contains('"') {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        arg.to_owned()
+    }
+}
+
+fn tables(rules: &RuleSet) -> Vec<(Family, Table)> {
+    let mut tables = Vec::new();
+    for chain in &rules.chains {
+        if !tables.contains(&(chain.family, chain.table)) {
+            tables.push((chain.family, chain.table));
+        }
+    }
+    tables
+}
+
+fn chains(rules: &RuleSet, family: Family, table: Table) -> Vec<&Chain> {
+    rules
+        .chains
+        .iter()
+        .filter(|c| c.family == family && c.table == table)
+        .collect()
+}
+
+/// `iptables-restore --noflush` input creating and hooking the chains of one table.
+pub fn install_payload(rules: &RuleSet, family: Family, table: Table) -> String {
+    let chains = chains(rules, family, table);
+    let mut payload = format!("*{}\n", table);
+    for chain in &chains {
+        writeln!(payload, ":{} - [0:0]", chain.name).unwrap();
+    }
+    for chain in &chains {
+        writeln!(payload, "-A {} -j {}", chain.hook, chain.name).unwrap();
+        for rule in &chain.rules {
+            let args: Vec<String> = rule_args(rule).iter().map(|a| quote(a)).collect();
+            writeln!(payload, "-A {} {}", chain.name, args.join(" ")).unwrap();
+        }
+    }
+    payload.push_str("COMMIT\n");
+    payload
+}
+
+/// `iptables-restore --noflush` input unhooking and deleting the chains of one table.
+pub fn uninstall_payload(rules: &RuleSet, family: Family, table: Table) -> String {
+    let chains = chains(rules, family, table);
+    let mut payload = format!("*{}\n", table);
+    for chain in &chains {
+        writeln!(payload, "-D {} -j {}", chain.hook, chain.name).unwrap();
+        writeln!(payload, "-F {}", chain.name).unwrap();
+        writeln!(payload, "-X {}", chain.name).unwrap();
+    }
+    payload.push_str("COMMIT\n");
+    payload
+}
+
+fn restore_command(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "iptables-restore",
+        Family::V6 => "ip6tables-restore",
+    }
+}
+
+fn save_command(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "iptables-save",
+        Family::V6 => "ip6tables-save",
+    }
+}
+
+/// Chains currently in the kernel whose name starts with `prefix`, without their rules.
+/// Chains that are not hooked into `OUTPUT` or `PREROUTING` are left out.
+pub fn list_chains(family: Family, prefix: &str) -> Result<Vec<Chain>> {
+    let command = save_command(family);
+    let saved = cmd_lib::run_fun! { ${command} }?;
+    let mut chains = Vec::new();
+    let mut table = None;
+    for line in saved.lines() {
+        if let Some(name) = line.strip_prefix('*') {
+            table = match name {
+                "nat" => Some(Table::Nat),
+                "mangle" => Some(Table::Mangle),
+                "raw" => Some(Table::Raw),
+                "filter" => Some(Table::Filter),
+                _ => None,
+            };
+            continue;
+        }
+        let table = match table {
+            Some(table) => table,
+            None => continue,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let ["-A", hook, "-j", name] = words[..] {
+            let hook = match hook {
+                "OUTPUT" => Hook::Output,
+                "PREROUTING" => Hook::Prerouting,
+                _ => continue,
+            };
+            if name.starts_with(prefix) {
+                chains.push(Chain::new(name, family, table, hook));
+            }
+        }
+    }
+    Ok(chains)
+}
+
+/// Counters of every chain in `rules`, in the same order.
+pub fn counters(rules: &RuleSet) -> Result<Vec<Counters>> {
+    let mut counters = vec![Counters::default(); rules.chains.len()];
+    for (family, table) in tables(rules) {
+        let command = save_command(family);
+        let table_name = table.to_string();
+        let saved = cmd_lib::run_fun! { ${command} -c -t ${table_name} }?;
+        for line in saved.lines() {
+            // [packets:bytes] -A chain ...
+            let (packets, bytes, rule) = match line
+                .strip_prefix('[')
+                .and_then(|line| line.split_once(']'))
+                .and_then(|(counts, rule)| {
+                    let (packets, bytes) = counts.split_once(':')?;
+                    Some((
+                        packets.parse::<u64>().ok()?,
+                        bytes.parse::<u64>().ok()?,
+                        rule,
+                    ))
+                }) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let words: Vec<&str> = rule.split_whitespace().collect();
+            if words.ends_with(&["-j", "RETURN"]) {
+                continue;
+            }
+            if let ["-A", name, ..] = words[..] {
+                for (chain, counter) in rules.chains.iter().zip(counters.iter_mut()) {
+                    if chain.name == name && chain.family == family && chain.table == table {
+                        counter.packets += packets;
+                        counter.bytes += bytes;
+                    }
+                }
+            }
+        }
+    }
+    Ok(counters)
+}
+
+/// `ipset restore` input creating the sets of `rules`, replacing what a crashed session left.
+pub fn ipset_payload(rules: &RuleSet) -> String {
+    let mut payload = String::new();
+    for set in &rules.sets {
+        let family = match set.family {
+            Family::V4 => "inet",
+            Family::V6 => "inet6",
+        };
+        if set.timeout {
+            writeln!(
+                payload,
+                "create {} hash:ip family {} timeout {} -exist",
+                set.name, family, DEFAULT_TIMEOUT
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                payload,
+                "create {} hash:net family {} maxelem {} -exist",
+                set.name,
+                family,
+                set.nets.len().max(65536)
+            )
+            .unwrap();
+        }
+        writeln!(payload, "flush {}", set.name).unwrap();
+        for net in &set.nets {
+            writeln!(payload, "add {} {} -exist", set.name, net).unwrap();
+        }
+    }
+    payload
+}
+
+/// Names of the ipsets currently in the kernel that start with `prefix`.
+pub fn list_sets(prefix: &str) -> Result<Vec<String>> {
+    let sets = cmd_lib::run_fun! { ipset list -n }?;
+    Ok(sets
+        .lines()
+        .map(str::trim)
+        .filter(|name| name.starts_with(prefix))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Adds `ip` to the ipset `set` for `timeout` seconds, or renews it.
+pub fn add_to_set(set: &str, ip: IpAddr, timeout: u32) -> Result<()> {
+    (cmd_lib::run_cmd! { ipset add ${set} ${ip} timeout ${timeout} -exist })?;
+    Ok(())
+}
+
+/// Destroys the ipset `name`. It can only go once no chain references it anymore.
+pub fn destroy_set(name: &str) -> Result<()> {
+    (cmd_lib::run_cmd! { ipset destroy ${name} })?;
+    Ok(())
+}
+
+/// Destroys the ipsets of `rules`, see [`destroy_set`].
+fn destroy_sets(rules: &RuleSet) -> Result<()> {
+    let mut result = Ok(());
+    for set in &rules.sets {
+        if let Err(e) = destroy_set(&set.name) {
+            tracing::error!("failed to destroy ipset {}. error: {}", set.name, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+fn restore(family: Family, payload: &str) -> Result<()> {
+    let command = restore_command(family);
+    tracing::debug!("applying {} payload:\n{}", command, payload);
+    pipe_to(command, &["--noflush"], payload)
+}
+
+/// Shell commands doing what [`install`] does.
+pub fn install_commands(rules: &RuleSet) -> Vec<String> {
+    let mut commands = Vec::new();
+    if !rules.sets.is_empty() {
+        commands.push(format!(
+            "ipset restore <<'EOF'\n{}EOF",
+            ipset_payload(rules)
+        ));
+    }
+    commands.extend(tables(rules).into_iter().map(|(family, table)| {
+        format!(
+            "{} --noflush <<'EOF'\n{}EOF",
+            restore_command(family),
+            install_payload(rules, family, table)
+        )
+    }));
+    commands
+}
+
+/// Shell commands doing what [`uninstall`] does.
+pub fn uninstall_commands(rules: &RuleSet) -> Vec<String> {
+    let mut commands: Vec<String> = tables(rules)
+        .into_iter()
+        .rev()
+        .map(|(family, table)| {
+            format!(
+                "{} --noflush <<'EOF'\n{}EOF",
+                restore_command(family),
+                uninstall_payload(rules, family, table)
+            )
+        })
+        .collect();
+    commands.extend(
+        rules
+            .sets
+            .iter()
+            .map(|s| format!("ipset destroy {}", s.name)),
+    );
+    commands
+}
+
+/// Each table is committed atomically by `iptables-restore`. If a later table is rejected,
+/// the tables that already went in are removed again so nothing is left half installed.
+pub fn install(rules: &RuleSet) -> Result<()> {
+    if !rules.sets.is_empty() {
+        let payload = ipset_payload(rules);
+        tracing::debug!("creating {} ipsets", rules.sets.len());
+        pipe_to("ipset", &["restore"], &payload)
+            .map_err(|e| e.wrap_err("failed to create ipsets"))?;
+    }
+    let mut applied = Vec::new();
+    for (family, table) in tables(rules) {
+        if let Err(e) = restore(family, &install_payload(rules, family, table)) {
+            for (family, table) in applied.into_iter().rev() {
+                if let Err(e) = restore(family, &uninstall_payload(rules, family, table)) {
+                    tracing::error!("failed to roll back {} table. error: {}", table, e);
+                }
+            }
+            let _ = destroy_sets(rules);
+            return Err(e.wrap_err(format!(
+                "failed to install rules in {} table with {}",
+                table,
+                restore_command(family)
+            )));
+        }
+        applied.push((family, table));
+    }
+    Ok(())
+}
+
+/// Removes every table's chains and then the sets, even if some of them fail, and reports the
+/// first error.
+pub fn uninstall(rules: &RuleSet) -> Result<()> {
+    let mut result = Ok(());
+    for (family, table) in tables(rules).into_iter().rev() {
+        if let Err(e) = restore(family, &uninstall_payload(rules, family, table)) {
+            tracing::error!("failed to remove rules from {} table. error: {}", table, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    if let Err(e) = destroy_sets(rules) {
+        if result.is_ok() {
+            result = Err(e);
+        }
+    }
+    result
+}
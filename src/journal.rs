@@ -0,0 +1,208 @@
+//! Crash-safe record of what a session created.
+//!
+//! Every object is written to `/run/cproxy/<session>.json` before it is created, and the file is
+//! only removed after the session tore everything down again. If cproxy is killed or panics in
+//! between, `cproxy cleanup` finds the journal and removes exactly what it lists.
+
+use crate::backend::Backend;
+use crate::netlink::PolicyRoute;
+use crate::rules::RuleSet;
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const JOURNAL_DIR: &str = "/run/cproxy";
+
+/// Everything one session created, as stored in its journal.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// Pid of the cproxy process running the session.
+    pub owner: u32,
+    /// Start time of the owner, so a process that got its pid after a crash isn't taken for it.
+    /// `None` in journals of older versions.
+    pub owner_start: Option<u64>,
+    pub mode: String,
+    pub port: u32,
+    pub class_id: u32,
+    /// The session's cgroup, as given to cgroups-rs.
+    pub cgroup: Option<String>,
+    pub backend: Option<Backend>,
+    pub rules: Option<RuleSet>,
+    pub routes: Vec<PolicyRoute>,
+    pub bpf_pin_dir: Option<PathBuf>,
+}
+
+impl Session {
+    fn new(class_id: u32) -> Self {
+        let owner = std::process::id();
+        Self {
+            owner,
+            owner_start: start_time(owner),
+            class_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn owner_alive(&self) -> bool {
+        match (start_time(self.owner), self.owner_start) {
+            (None, _) => false,
+            (Some(start), Some(owner_start)) => start == owner_start,
+            (Some(_), None) => true,
+        }
+    }
+}
+
+/// When `pid` started, in clock ticks after boot, field 22 of `/proc/<pid>/stat`.
+fn start_time(pid: u32) -> Option<u64> {
+    let stat =
+        std::fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("stat")).ok()?;
+    // The command name in parentheses may contain spaces, field 3 follows the last `)`.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+pub fn journal_path(session_name: &str) -> PathBuf {
+    Path::new(JOURNAL_DIR).join(format!("{}.json", session_name))
+}
+
+/// The journal of the running session. Dropping it removes the file, unless cproxy is
+/// panicking or [`Journal::keep`] was called because something could not be removed.
+pub struct Journal {
+    /// `None` for journals that are only kept in memory, in tests.
+    path: Option<PathBuf>,
+    session: Session,
+    keep: bool,
+}
+
+impl Journal {
+    /// Claims `session_name` by creating its journal. `None` if the journal exists already, as
+    /// another session that started at the same time picked the same class id.
+    pub fn create(session_name: &str, class_id: u32) -> Result<Option<Self>> {
+        std::fs::create_dir_all(JOURNAL_DIR)?;
+        Self::create_at(journal_path(session_name), class_id)
+    }
+
+    /// Like [`Journal::create`]. The file is created with `O_EXCL`, so only one session wins.
+    pub fn create_at(path: PathBuf, class_id: u32) -> Result<Option<Self>> {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(None),
+            Err(e) => return Err(eyre!("failed to create journal {}: {}", path.display(), e)),
+        };
+        let journal = Self {
+            path: Some(path),
+            session: Session::new(class_id),
+            keep: false,
+        };
+        // Dropping the journal removes the file again if it can't be written.
+        file.write_all(&serde_json::to_vec_pretty(&journal.session)?)
+            .map_err(|e| eyre!("failed to write journal: {}", e))?;
+        Ok(Some(journal))
+    }
+
+    #[cfg(test)]
+    pub fn in_memory(class_id: u32) -> Self {
+        Self {
+            path: None,
+            session: Session::new(class_id),
+            keep: false,
+        }
+    }
+
+    /// Applies `update` and writes the journal, replacing the old file atomically.
+    pub fn record(&mut self, update: impl FnOnce(&mut Session)) -> Result<()> {
+        update(&mut self.session);
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.session)?)?;
+        std::fs::rename(&tmp, path)
+            .map_err(|e| eyre!("failed to write journal {}: {}", path.display(), e))
+    }
+
+    #[cfg(test)]
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if self.keep || std::thread::panicking() {
+            tracing::warn!(
+                "session was not cleaned up completely, run `cproxy cleanup` to remove what is left. journal: {}",
+                path.display()
+            );
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("failed to remove journal. error: {}", e);
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Session> {
+    let content = std::fs::read(path)?;
+    serde_json::from_slice(&content).map_err(|e| eyre!("invalid journal {}: {}", path.display(), e))
+}
+
+/// All journals in [`JOURNAL_DIR`], live or stale.
+pub fn load_all() -> Result<Vec<(PathBuf, Session)>> {
+    let entries = match std::fs::read_dir(JOURNAL_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut sessions = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        match load(&path) {
+            Ok(session) => sessions.push((path, session)),
+            Err(e) => tracing::warn!("skipping journal. error: {}", e),
+        }
+    }
+    sessions.sort_by_key(|(path, _)| path.clone());
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_session_claims_a_journal() {
+        let path = std::env::temp_dir().join(format!("cproxy-claim-{}.json", std::process::id()));
+        let first = Journal::create_at(path.clone(), 42).unwrap();
+        assert!(first.is_some());
+        assert!(Journal::create_at(path.clone(), 42).unwrap().is_none());
+        assert_eq!(load(&path).unwrap().class_id, 42);
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reused_owner_pid_is_not_alive() {
+        let mut session = Session::new(42);
+        assert!(session.owner_alive());
+        session.owner_start = session.owner_start.map(|start| start + 1);
+        assert!(!session.owner_alive());
+    }
+}
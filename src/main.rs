@@ -1,8 +1,20 @@
 #![allow(dyn_drop)]
 
+use crate::backend::{Backend, BackendChoice};
+use crate::dns::DomainPattern;
+use crate::executor::System;
 use crate::guards::TraceGuard;
-use eyre::Result;
-use guards::{CGroupGuard, RedirectGuard, TProxyGuard};
+use crate::netlink::TableRange;
+use crate::plan::{CgroupSpec, Plan};
+use crate::resolv::{HostEntry, PrivateFiles};
+use crate::rules::{Cidr, Destination, Mark, PortMapping, PortRange};
+use eyre::{eyre, Result};
+use guards::{
+    BlockGuard, BpfRedirectGuard, CGroupGuard, RedirectGuard, SessionOptions, TProxyGuard,
+    UdpPolicy,
+};
+use nix::unistd::{Gid, Uid};
+use std::net::IpAddr;
 use std::os::unix::prelude::CommandExt;
 use std::process::ExitStatus;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -10,25 +22,161 @@ use std::sync::Arc;
 use std::time::Duration;
 use structopt::StructOpt;
 
+mod backend;
+mod bpf;
+mod class_id;
+mod cleanup;
+mod dns;
+mod doctor;
+mod executor;
 mod guards;
+mod iplist;
+mod iptables;
+mod journal;
+mod netlink;
+mod nftables;
+mod plan;
+mod resolv;
+mod rules;
+mod status;
 
 #[derive(StructOpt, Debug)]
 struct Cli {
     /// Redirect traffic to specific local port.
     #[structopt(long, env = "CPROXY_PORT", default_value = "1080")]
-    port: u32,
+    port: u16,
 
-    /// redirect DNS traffic. This option only works with redirect mode
+    /// Proxy port for TCP traffic, `--port` if not given.
+    #[structopt(long)]
+    tcp_port: Option<u16>,
+
+    /// Proxy port for UDP traffic, `--port` if not given.
+    #[structopt(long)]
+    udp_port: Option<u16>,
+
+    /// Proxy port for DNS traffic, `--udp-port` if not given. Tor's `DNSPort`, for example.
+    #[structopt(long)]
+    dns_port: Option<u16>,
+
+    /// Send traffic to some destination ports to another proxy port, like `80,443=8118` or
+    /// `8000-8080=3128`. Can be specified multiple times, the first matching one wins. DNS keeps
+    /// going to `--dns-port`.
+    #[structopt(long)]
+    port_map: Vec<PortMapping>,
+
+    /// What to do with UDP traffic redirect mode can't proxy, everything but DNS: `direct` lets it
+    /// go out directly, `reject` refuses it so programs fall back to TCP (browsers and gRPC
+    /// clients on UDP/443, for example), `drop` silently drops it.
+    #[structopt(long, default_value = "direct", possible_values = &["direct", "reject", "drop"])]
+    udp_policy: UdpPolicy,
+
+    /// redirect DNS traffic, over UDP and TCP. This option only works with redirect mode
     #[structopt(long)]
     redirect_dns: bool,
 
-    /// Proxy mode can be `trace` (use iptables TRACE target to debug program network), `tproxy`, or `redirect`.
+    /// Proxy mode can be `trace` (use iptables TRACE target to debug program network), `tproxy`,
+    /// `redirect`, or `block` (deny the program all network access).
     #[structopt(long, default_value = "redirect")]
     mode: String,
 
-    /// Override dns server address. This option only works with tproxy mode
+    /// Let a blocked program reach loopback. Only works with block mode.
+    #[structopt(long)]
+    allow_loopback: bool,
+
+    /// Let a blocked program reach this destination range, e.g. `10.0.0.0/8` or `2001:db8::1`.
+    /// Can be specified multiple times. Only works with block mode.
+    #[structopt(long)]
+    allow_dst: Vec<Cidr>,
+
+    /// Log what block mode rejects to the kernel log, like trace mode does.
+    #[structopt(long)]
+    log_blocked: bool,
+
+    /// Send DNS traffic to this server instead, `ip` or `ip:port`. Can be specified multiple
+    /// times to spread connections over several servers, each one only replaces DNS traffic of
+    /// its own address family.
+    #[structopt(long)]
+    override_dns: Vec<Destination>,
+
+    /// Also proxy IPv6 traffic. Your proxy needs to listen on `::1` (or `::`) as well.
+    #[structopt(long)]
+    ipv6: bool,
+
+    /// Let IPv6 traffic bypass the proxy. Without `--ipv6`, IPv6 traffic is rejected by default so
+    /// programs fall back to IPv4 instead of leaking around the proxy.
+    #[structopt(long)]
+    allow_ipv6_leak: bool,
+
+    /// fwmark for tproxy mode as `value` or `value/mask`, e.g. `0x100/0xff00`. With a mask only
+    /// those bits are set (`--set-xmark`), so other tools' mark bits are left alone. Defaults to
+    /// the class id with a full mask. Concurrent tproxy sessions need distinct values.
+    #[structopt(long)]
+    fwmark: Option<Mark>,
+
+    /// Routing table for tproxy mode, a fixed id like `100` or a range like `100-199` to take the
+    /// first unused table from. Defaults to the class id.
+    #[structopt(long)]
+    route_table: Option<TableRange>,
+
+    /// Priority of the tproxy mode `ip rule`. Defaults to the kernel's choice.
+    #[structopt(long)]
+    rule_priority: Option<u32>,
+
+    /// Send traffic to this destination range directly instead of through the proxy, e.g.
+    /// `203.0.113.0/24` or `2001:db8::1`. Can be specified multiple times.
     #[structopt(long)]
-    override_dns: Option<String>,
+    bypass: Vec<Cidr>,
+
+    /// Read more `--bypass` ranges from a file into a kernel set (ipset or nftables set): plain
+    /// text with one range per line and `#` comments, or a MaxMind GeoLite2 blocks CSV or v2ray
+    /// `geoip.dat` with a country code like `geoip.dat:cn`. Can be specified multiple times.
+    #[structopt(long)]
+    bypass_file: Vec<String>,
+
+    /// Proxy private, CGNAT, link-local and multicast destinations too. They bypass the proxy by
+    /// default.
+    #[structopt(long)]
+    no_default_bypass: bool,
+
+    /// Only proxy traffic to this destination range and let everything else go direct. Can be
+    /// specified multiple times. `--bypass` ranges still go direct.
+    #[structopt(long)]
+    only_dst: Vec<Cidr>,
+
+    /// Read more `--only-dst` ranges from a file, in the formats of `--bypass-file`. Can be
+    /// specified multiple times.
+    #[structopt(long)]
+    only_dst_file: Vec<String>,
+
+    /// Only proxy traffic to this destination port or range like `8000-8080`. Can be specified
+    /// multiple times, and combined with `--only-dst`.
+    #[structopt(long)]
+    only_dport: Vec<PortRange>,
+
+    /// Only proxy traffic to the addresses of this domain and its subdomains, like `github.com`
+    /// or `*.googleapis.com`, and let everything else go direct. The session's DNS queries go
+    /// through a forwarder that learns the addresses from the answers, they expire with their TTL.
+    /// The forwarder asks `--override-dns`, or the nameserver of `/etc/resolv.conf`. Can be
+    /// specified multiple times.
+    #[structopt(long, conflicts_with_all = &["only-dst", "only-dst-file"])]
+    proxy_domain: Vec<DomainPattern>,
+
+    /// Packet filtering backend, can be `auto`, `iptables`, `nftables` or `ebpf`. `auto` uses
+    /// nftables when iptables is missing or only a wrapper around nf_tables. `ebpf` attaches cgroup
+    /// socket hooks instead of netfilter rules and only works with redirect mode on cgroup v2.
+    #[structopt(long, env = "CPROXY_BACKEND", default_value = "auto")]
+    backend: BackendChoice,
+
+    /// Give the program its own `/etc/resolv.conf` with this nameserver, bind-mounted in a private
+    /// mount namespace. Covers resolvers DNS rules can't, like systemd-resolved on `127.0.0.53`.
+    /// Can be specified multiple times. Only for a new program, not `--pid` or `--cgroup-path`.
+    #[structopt(long, conflicts_with_all = &["pid", "cgroup-path"])]
+    dns: Vec<IpAddr>,
+
+    /// Add a `name:ip` entry to the program's own `/etc/hosts`, like `db.internal:10.0.0.5`. Can
+    /// be specified multiple times. Only for a new program, like `--dns`.
+    #[structopt(long, conflicts_with_all = &["pid", "cgroup-path"])]
+    add_host: Vec<HostEntry>,
 
     /// Proxy an existing process.
     #[structopt(long)]
@@ -38,82 +186,425 @@ struct Cli {
     #[structopt(long)]
     cgroup_path: Vec<String>,
 
+    /// Don't check the host for the kernel modules, tools and permissions the mode needs before
+    /// setting it up. See `cproxy doctor`.
+    #[structopt(long)]
+    skip_preflight: bool,
+
+    /// Print what would be set up and torn down, without changing anything, and exit.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Output of `--dry-run`, `shell` or `json`.
+    #[structopt(long, default_value = "shell", possible_values = &["shell", "json"])]
+    dry_run_format: String,
+
     #[structopt(subcommand)]
     command: Option<ChildCommand>,
 }
 
 #[derive(StructOpt, Debug)]
 enum ChildCommand {
+    /// Remove rules, routes and cgroups left behind by cproxy sessions that did not exit cleanly.
+    Cleanup {
+        /// Also remove chains like `cp_rd_out_<id>`, ipsets like `cp_bypass_<id>`, `cproxy_<id>`
+        /// nftables tables and `cproxy-<id>` cgroups that no running session owns, even without a
+        /// journal (e.g. from older cproxy versions).
+        #[structopt(long)]
+        orphans: bool,
+    },
+    /// Show the cproxy sessions on this host, with their rules, counters and processes.
+    #[structopt(alias = "list")]
+    Status {
+        /// Print JSON instead of text.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Check what each mode needs from this host (cgroups, kernel modules, iptables, routing and
+    /// permissions) and report what is missing. Also done before every session.
+    Doctor {
+        /// Print JSON instead of text.
+        #[structopt(long)]
+        json: bool,
+    },
     #[structopt(external_subcommand)]
     Command(Vec<String>),
 }
 
-fn proxy_new_command(args: &Cli) -> Result<ExitStatus> {
-    let pid = std::process::id();
-    let ChildCommand::Command(child_command) = &args
-        .command
-        .as_ref()
-        .expect("must have command specified if --pid not provided");
-    tracing::info!("subcommand {:?}", child_command);
+/// Ranges from `--bypass` and the defaults.
+fn bypass(args: &Cli) -> Result<Vec<Cidr>> {
+    let mut bypass = Vec::new();
+    if !args.no_default_bypass {
+        for net in plan::DEFAULT_BYPASS.iter() {
+            bypass.push(net.parse()?);
+        }
+    }
+    bypass.extend(args.bypass.iter().copied());
+    Ok(bypass)
+}
 
-    let port = args.port;
+/// Ranges from all `files`, see [`iplist`].
+fn load_lists(files: &[String]) -> Result<Vec<Cidr>> {
+    let mut nets = Vec::new();
+    for file in files {
+        nets.extend(iplist::load(file)?);
+    }
+    Ok(nets)
+}
 
-    let cgroup_guard = CGroupGuard::new(pid)?;
-    let _guard: Box<dyn Drop> = match args.mode.as_str() {
-        "redirect" => {
-            let output_chain_name = format!("cp_rd_out_{}", pid);
-            Box::new(RedirectGuard::new(
-                port,
-                output_chain_name.as_str(),
-                cgroup_guard,
-                args.redirect_dns,
-            )?)
+fn session_options(args: &Cli) -> Result<SessionOptions> {
+    Ok(SessionOptions {
+        redirect_dns: args.redirect_dns,
+        override_dns: args.override_dns.clone(),
+        ipv6: args.ipv6,
+        // Trace mode doesn't proxy anything, so there is nothing to leak. Block mode blocks both.
+        block_ipv6: args.mode == "block"
+            || (!args.ipv6 && !args.allow_ipv6_leak && args.mode != "trace"),
+        route_table: args.route_table,
+        rule_priority: args.rule_priority,
+        bypass: bypass(args)?,
+        bypass_files: load_lists(&args.bypass_file)?,
+        only_dst: args.only_dst.clone(),
+        only_dst_files: load_lists(&args.only_dst_file)?,
+        only_dport: args.only_dport.clone(),
+        proxy_domains: args.proxy_domain.clone(),
+        snoop_port: 0,
+        tcp_port: args.tcp_port,
+        udp_port: args.udp_port,
+        dns_port: args.dns_port,
+        port_map: args.port_map.clone(),
+        udp_policy: args.udp_policy,
+        allow_loopback: args.allow_loopback,
+        allow_dst: args.allow_dst.clone(),
+        log_blocked: args.log_blocked,
+    })
+}
+
+fn check_mode(args: &Cli, backend: Backend) -> Result<()> {
+    if let Some(server) = args.override_dns.iter().find(|d| d.ip.is_ipv6()) {
+        if !args.ipv6 {
+            return Err(eyre!(
+                "--override-dns {} is an IPv6 server, IPv6 is only proxied with --ipv6",
+                server
+            ));
+        }
+    }
+    let port_lists = std::iter::once(("--only-dport", &args.only_dport))
+        .chain(args.port_map.iter().map(|m| ("--port-map", &m.dports)));
+    for (option, ports) in port_lists {
+        let specs = iptables::multiport_specs(&plan::merge_ports(ports));
+        if backend == Backend::Iptables && specs > iptables::MULTIPORT_LIMIT {
+            return Err(eyre!(
+                "iptables matches at most {} ports per rule and a range counts as two, but {} has {}; \
+                 use fewer ports or --backend nftables",
+                iptables::MULTIPORT_LIMIT,
+                option,
+                specs
+            ));
+        }
+    }
+    match args.mode.as_str() {
+        "redirect" if backend == Backend::Ebpf && args.ipv6 => {
+            Err(eyre!("the ebpf backend does not support --ipv6 yet"))
+        }
+        "redirect" if backend == Backend::Ebpf && !args.proxy_domain.is_empty() => Err(eyre!(
+            "the ebpf backend does not support --proxy-domain yet"
+        )),
+        "redirect" if backend == Backend::Ebpf && !args.override_dns.is_empty() => Err(eyre!(
+            "the ebpf backend does not support --override-dns yet"
+        )),
+        "redirect"
+            if args.redirect_dns
+                && !args.override_dns.is_empty()
+                && args.proxy_domain.is_empty() =>
+        {
+            Err(eyre!(
+                "DNS goes either to the proxy or to the --override-dns servers, drop --redirect-dns"
+            ))
+        }
+        "redirect" if backend == Backend::Ebpf && args.udp_policy == UdpPolicy::Drop => Err(eyre!(
+            "the ebpf backend can only reject UDP, use --udp-policy reject"
+        )),
+        "tproxy" | "trace" | "block" if backend == Backend::Ebpf => {
+            Err(eyre!("the ebpf backend only supports redirect mode"))
+        }
+        "tproxy" | "trace" | "block" if args.udp_policy != UdpPolicy::Direct => {
+            Err(eyre!("--udp-policy only works with redirect mode"))
         }
+        "trace" | "block" if !args.proxy_domain.is_empty() => Err(eyre!(
+            "--proxy-domain only works with redirect and tproxy mode"
+        )),
+        "trace" | "block" if !args.override_dns.is_empty() => Err(eyre!(
+            "--override-dns only works with redirect and tproxy mode"
+        )),
+        "block" if args.allow_ipv6_leak => Err(eyre!(
+            "block mode blocks IPv6 as well, drop --allow-ipv6-leak"
+        )),
+        mode if mode != "block"
+            && (args.allow_loopback || !args.allow_dst.is_empty() || args.log_blocked) =>
+        {
+            Err(eyre!(
+                "--allow-loopback, --allow-dst and --log-blocked only work with block mode"
+            ))
+        }
+        "redirect" | "tproxy" | "trace" | "block" => Ok(()),
+        mode => Err(eyre!("unknown mode `{}`", mode)),
+    }
+}
+
+fn new_guard(args: &Cli, mut cgroup_guard: CGroupGuard, backend: Backend) -> Result<Box<dyn Drop>> {
+    check_mode(args, backend)?;
+    let options = session_options(args)?;
+    if !args.skip_preflight {
+        doctor::preflight(&args.mode, backend, &options)?;
+    }
+    let port = u32::from(args.port);
+    let id = cgroup_guard.spec.class_id;
+    cgroup_guard.journal.record(|s| {
+        s.mode = args.mode.clone();
+        s.port = port;
+    })?;
+    let (output_chain_name, prerouting_chain_name) = plan::chain_names(&args.mode, id);
+    let guard: Box<dyn Drop> = match args.mode.as_str() {
+        "redirect" if backend == Backend::Ebpf => {
+            Box::new(BpfRedirectGuard::new(port, cgroup_guard, &options)?)
+        }
+        "redirect" => Box::new(RedirectGuard::new(
+            port,
+            &output_chain_name,
+            cgroup_guard,
+            backend,
+            &options,
+        )?),
         "tproxy" => {
-            let output_chain_name = format!("cp_tp_out_{}", pid);
-            let prerouting_chain_name = format!("cp_tp_pre_{}", pid);
-            let mark = pid;
+            let mark = args.fwmark.unwrap_or_else(|| Mark::new(id));
             Box::new(TProxyGuard::new(
                 port,
                 mark,
-                output_chain_name.as_str(),
-                prerouting_chain_name.as_str(),
+                &output_chain_name,
+                &prerouting_chain_name,
                 cgroup_guard,
-                args.override_dns.clone(),
+                backend,
+                &options,
             )?)
         }
-        "trace" => {
-            let prerouting_chain_name = format!("cp_tr_pre_{}", pid);
-            let output_chain_name = format!("cp_tr_out_{}", pid);
-            Box::new(TraceGuard::new(
-                output_chain_name.as_str(),
-                prerouting_chain_name.as_str(),
-                cgroup_guard,
-            )?)
+        "block" => Box::new(BlockGuard::new(
+            &output_chain_name,
+            cgroup_guard,
+            backend,
+            &options,
+        )?),
+        _ => Box::new(TraceGuard::new(
+            &output_chain_name,
+            &prerouting_chain_name,
+            cgroup_guard,
+            backend,
+            &options,
+        )?),
+    };
+    Ok(guard)
+}
+
+/// What [`new_guard`] would set up for `cgroup`, without setting anything up.
+fn plan_session(
+    args: &Cli,
+    cgroup: CgroupSpec,
+    pid: Option<u32>,
+    backend: Backend,
+) -> Result<Plan> {
+    check_mode(args, backend)?;
+    let id = cgroup.class_id;
+    let port = u32::from(args.port);
+    // The forwarder's port is only picked when the session starts, the rules show it as 0.
+    let options = session_options(args)?;
+    let (output_chain_name, prerouting_chain_name) = plan::chain_names(&args.mode, id);
+    let (rules, routes) = match args.mode.as_str() {
+        "redirect" if backend == Backend::Ebpf && !cgroup.hier_v2 => {
+            return Err(eyre!(
+                "the ebpf backend needs the unified cgroup v2 hierarchy"
+            ));
+        }
+        "redirect" if backend == Backend::Ebpf => (None, Vec::new()),
+        "redirect" => (
+            Some(plan::redirect_rules(
+                port,
+                &output_chain_name,
+                &cgroup,
+                &options,
+            )),
+            Vec::new(),
+        ),
+        "tproxy" => {
+            let mark = args.fwmark.unwrap_or_else(|| Mark::new(id));
+            let table = plan::route_table(&options, id)?;
+            let rules = plan::tproxy_rules(
+                port,
+                mark,
+                &output_chain_name,
+                &prerouting_chain_name,
+                &cgroup,
+                &options,
+            );
+            let routes =
+                plan::policy_routes(mark, table, options.rule_priority, &options.families());
+            (Some(rules), routes)
         }
-        &_ => {
-            unimplemented!()
+        "block" => (
+            Some(plan::block_rules(&output_chain_name, &cgroup, &options)),
+            Vec::new(),
+        ),
+        _ => (
+            Some(plan::trace_rules(&output_chain_name, &cgroup, &options)),
+            Vec::new(),
+        ),
+    };
+    let mut plan = Plan::new(&args.mode, port, backend, cgroup, pid, rules, routes)?;
+    plan.notes.push(format!(
+        "class id {} unless it is in use when the session starts, then the next free one",
+        id
+    ));
+    if !options.proxy_domains.is_empty() {
+        plan.notes.push(
+            "the DNS forwarder listens on a loopback port picked when the session starts, shown as 0"
+                .to_owned(),
+        );
+    }
+    Ok(plan)
+}
+
+fn dry_run(args: &Cli) -> Result<()> {
+    let backend = args.backend.resolve();
+    let mut plans = Vec::new();
+    if !args.cgroup_path.is_empty() {
+        for path in &args.cgroup_path {
+            plans.push(plan_session(
+                args,
+                CgroupSpec::for_path(path, |id| Ok(class_id::unclaimed(id)))?,
+                None,
+                backend,
+            )?);
+        }
+    } else {
+        // Without --pid, cproxy moves itself into the cgroup before starting the command.
+        let pid = args.pid.unwrap_or_else(std::process::id);
+        plans.push(plan_session(
+            args,
+            CgroupSpec::for_pid(pid, |id| Ok(class_id::unclaimed(id)))?,
+            Some(pid),
+            backend,
+        )?);
+    }
+    if args.dry_run_format == "json" {
+        println!("{}", serde_json::to_string_pretty(&plans)?);
+    } else {
+        for plan in &plans {
+            plan.print_shell();
         }
+    }
+    Ok(())
+}
+
+/// Nameservers for the program's own resolv.conf: `--dns`, or the `--override-dns` servers if the
+/// host asks a stub resolver on loopback, which DNAT can't send elsewhere.
+fn private_dns(args: &Cli) -> Result<Vec<IpAddr>> {
+    if !args.dns.is_empty() || args.override_dns.is_empty() || !args.proxy_domain.is_empty() {
+        return Ok(args.dns.clone());
+    }
+    let stubs = resolv::loopback_nameservers();
+    if stubs.is_empty() {
+        return Ok(Vec::new());
+    }
+    // resolv.conf has no ports, the program would ask port 53 of the server instead.
+    if let Some(server) = args
+        .override_dns
+        .iter()
+        .find(|d| d.port.unwrap_or(53) != 53)
+    {
+        return Err(eyre!(
+            "the host resolves through {:?}, so the program gets its own resolv.conf with the \
+             --override-dns servers, which can't give a port like {}; pass --dns with a server on \
+             port 53",
+            stubs,
+            server
+        ));
+    }
+    tracing::info!(
+        "the host resolves through {:?}, giving the program its own resolv.conf with the \
+         --override-dns servers",
+        stubs
+    );
+    Ok(args.override_dns.iter().map(|d| d.ip).collect())
+}
+
+/// Warns if the session's DNS goes to a stub resolver on loopback, which resolves outside of the
+/// session, and nothing sends it elsewhere. `--redirect-dns` and `--proxy-domain` catch it.
+fn warn_loopback_dns(args: &Cli) {
+    if !args.dns.is_empty() || !args.proxy_domain.is_empty() {
+        return;
+    }
+    let new_program = args.pid.is_none() && args.cgroup_path.is_empty();
+    let what = match args.mode.as_str() {
+        _ if !args.override_dns.is_empty() && !new_program => "--override-dns",
+        "tproxy" if args.override_dns.is_empty() => "tproxy mode",
+        _ => return,
     };
+    if let Some(stub) = resolv::loopback_nameservers().first() {
+        tracing::warn!(
+            "DNS goes to the resolver on {} of /etc/resolv.conf, which {} can't redirect, so it is \
+             resolved outside of the proxy. Start the program through cproxy with --dns to give it \
+             its own nameserver",
+            stub,
+            what
+        );
+    }
+}
 
-    let sudo_uid = std::env::var("SUDO_UID").ok();
-    let sudo_gid = std::env::var("SUDO_GID").ok();
+fn proxy_new_command(args: &Cli) -> Result<ExitStatus> {
+    let pid = std::process::id();
+    let child_command = match &args.command {
+        Some(ChildCommand::Command(child_command)) => child_command,
+        _ => return Err(eyre!("must have command specified if --pid not provided")),
+    };
+    tracing::info!("subcommand {:?}", child_command);
+
+    let port = args.port;
+
+    let cgroup_guard = CGroupGuard::new(pid, Arc::new(System))?;
+    let _guard = new_guard(args, cgroup_guard, args.backend.resolve())?;
+
+    let sudo_uid: Option<u32> = std::env::var("SUDO_UID")
+        .ok()
+        .map(|uid| uid.parse().expect("invalid uid"));
+    let sudo_gid: Option<u32> = std::env::var("SUDO_GID")
+        .ok()
+        .map(|gid| gid.parse().expect("invalid gid"));
     let sudo_home = std::env::var("SUDO_HOME").ok();
 
     let original_uid = nix::unistd::getuid();
     let original_gid = nix::unistd::getgid();
     let mut command = std::process::Command::new(&child_command[0]);
-    if let Some(sudo_uid) = sudo_uid {
-        command.uid(sudo_uid.parse().expect("invalid uid"));
-    }
-    if let Some(sudo_gid) = sudo_gid {
-        command.gid(sudo_gid.parse().expect("invalid gid"));
+    let private_files = PrivateFiles::new(&private_dns(args)?, &args.add_host)?;
+    if let Some(files) = &private_files {
+        files.apply(
+            &mut command,
+            sudo_uid.map(Uid::from_raw),
+            sudo_gid.map(Gid::from_raw),
+        )?;
+    } else {
+        if let Some(sudo_uid) = sudo_uid {
+            command.uid(sudo_uid);
+        }
+        if let Some(sudo_gid) = sudo_gid {
+            command.gid(sudo_gid);
+        }
     }
     command.env("CPROXY_ENV", format!("cproxy/{}", port));
     if let Some(sudo_home) = sudo_home {
         command.env("HOME", sudo_home);
     }
     let mut child = command.args(&child_command[1..]).spawn()?;
+    // The program has its mounts by now.
+    drop(private_files);
     nix::unistd::seteuid(original_uid)?;
     nix::unistd::setegid(original_gid)?;
 
@@ -126,45 +617,8 @@ fn proxy_new_command(args: &Cli) -> Result<ExitStatus> {
 }
 
 fn proxy_existing_pid(pid: u32, args: &Cli) -> Result<()> {
-    let port = args.port;
-
-    let cgroup_guard = CGroupGuard::new(pid)?;
-    let _guard: Box<dyn Drop> = match args.mode.as_str() {
-        "redirect" => {
-            let output_chain_name = format!("cp_rd_out_{}", pid);
-            Box::new(RedirectGuard::new(
-                port,
-                output_chain_name.as_str(),
-                cgroup_guard,
-                args.redirect_dns,
-            )?)
-        }
-        "tproxy" => {
-            let output_chain_name = format!("cp_tp_out_{}", pid);
-            let prerouting_chain_name = format!("cp_tp_pre_{}", pid);
-            let mark = pid;
-            Box::new(TProxyGuard::new(
-                port,
-                mark,
-                output_chain_name.as_str(),
-                prerouting_chain_name.as_str(),
-                cgroup_guard,
-                args.override_dns.clone(),
-            )?)
-        }
-        "trace" => {
-            let prerouting_chain_name = format!("cp_tr_pre_{}", pid);
-            let output_chain_name = format!("cp_tr_out_{}", pid);
-            Box::new(TraceGuard::new(
-                output_chain_name.as_str(),
-                prerouting_chain_name.as_str(),
-                cgroup_guard,
-            )?)
-        }
-        _ => {
-            unimplemented!()
-        }
-    };
+    let cgroup_guard = CGroupGuard::new(pid, Arc::new(System))?;
+    let _guard = new_guard(args, cgroup_guard, args.backend.resolve())?;
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -182,49 +636,12 @@ fn proxy_existing_pid(pid: u32, args: &Cli) -> Result<()> {
 }
 
 fn proxy_cgroup_paths(paths: Vec<String>, args: &Cli) -> Result<()> {
-    let port = args.port;
-
+    let backend = args.backend.resolve();
     let mut guards: Vec<Box<dyn Drop>> = Vec::new();
 
     for path in paths {
-        let cgroup_guard = CGroupGuard::from_path(&path)?;
-        let guard: Box<dyn Drop> = match args.mode.as_str() {
-            "redirect" => {
-                let output_chain_name = format!("cp_rd_out_{}", cgroup_guard.class_id);
-                Box::new(RedirectGuard::new(
-                    port,
-                    output_chain_name.as_str(),
-                    cgroup_guard,
-                    args.redirect_dns,
-                )?)
-            }
-            "tproxy" => {
-                let output_chain_name = format!("cp_tp_out_{}", cgroup_guard.class_id);
-                let prerouting_chain_name = format!("cp_tp_pre_{}", cgroup_guard.class_id);
-                let mark = cgroup_guard.class_id;
-                Box::new(TProxyGuard::new(
-                    port,
-                    mark,
-                    output_chain_name.as_str(),
-                    prerouting_chain_name.as_str(),
-                    cgroup_guard,
-                    args.override_dns.clone(),
-                )?)
-            }
-            "trace" => {
-                let prerouting_chain_name = format!("cp_tr_pre_{}", cgroup_guard.class_id);
-                let output_chain_name = format!("cp_tr_out_{}", cgroup_guard.class_id);
-                Box::new(TraceGuard::new(
-                    output_chain_name.as_str(),
-                    prerouting_chain_name.as_str(),
-                    cgroup_guard,
-                )?)
-            }
-            _ => {
-                unimplemented!()
-            }
-        };
-        guards.push(guard);
+        let cgroup_guard = CGroupGuard::from_path(&path, Arc::new(System))?;
+        guards.push(new_guard(args, cgroup_guard, backend)?);
     }
 
     let running = Arc::new(AtomicBool::new(true));
@@ -245,15 +662,34 @@ fn proxy_cgroup_paths(paths: Vec<String>, args: &Cli) -> Result<()> {
 fn main() -> Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(tracing_subscriber::filter::LevelFilter::WARN.into())
+                .from_env_lossy(),
+        )
+        .with_writer(std::io::stderr)
         .init();
+    let args: Cli = Cli::from_args();
+    if args.dry_run {
+        return dry_run(&args);
+    }
+    if let Some(ChildCommand::Doctor { json }) = args.command {
+        // Reported as a missing requirement instead of failing here.
+        let _ = nix::unistd::seteuid(nix::unistd::Uid::from_raw(0));
+        return doctor::run(args.backend.resolve(), json);
+    }
     nix::unistd::seteuid(nix::unistd::Uid::from_raw(0))
         .expect("cproxy failed to seteuid, please run as root");
     nix::unistd::setegid(nix::unistd::Gid::from_raw(0))
         .expect("cproxy failed to seteuid, please run as root");
-    let args: Cli = Cli::from_args();
 
-    if args.cgroup_path.len() > 0 {
+    match args.command {
+        Some(ChildCommand::Cleanup { orphans }) => return cleanup::run(orphans),
+        Some(ChildCommand::Status { json }) => return status::run(json),
+        _ => {}
+    }
+    warn_loopback_dns(&args);
+    if !args.cgroup_path.is_empty() {
         proxy_cgroup_paths(args.cgroup_path.clone(), &args)?;
     } else {
         match args.pid {
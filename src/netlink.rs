@@ -0,0 +1,395 @@
+//! Just enough rtnetlink to manage the policy routing of tproxy mode without the `ip` binary.
+
+use crate::rules::{Family, Mark};
+use eyre::{eyre, Result};
+use netlink_packet_core::{
+    NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL,
+    NLM_F_REQUEST,
+};
+use netlink_packet_route::route::{
+    RouteAttribute, RouteHeader, RouteMessage, RouteProtocol, RouteScope, RouteType,
+};
+use netlink_packet_route::rule::{RuleAction, RuleAttribute, RuleHeader, RuleMessage};
+use netlink_packet_route::{AddressFamily, RouteNetlinkMessage};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+
+const RTNLGRP_IPV4_ROUTE: u32 = 7;
+const RTNLGRP_IPV4_RULE: u32 = 8;
+const RTNLGRP_IPV6_ROUTE: u32 = 11;
+const RTNLGRP_IPV6_RULE: u32 = 19;
+const RT_TABLE_LOCAL: u8 = 255;
+
+/// `ip rule add fwmark <mark> table <table> [priority <priority>]` plus
+/// `ip route add local 0.0.0.0/0 dev lo table <table>`, or their `ip -6` and `local ::/0` counterparts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRoute {
+    pub family: Family,
+    pub mark: Mark,
+    pub table: u32,
+    pub priority: Option<u32>,
+}
+
+impl PolicyRoute {
+    /// The `ip rule` and `ip route` commands adding (`verb` is `add`) or deleting (`del`) this.
+    pub fn ip_commands(&self, verb: &str) -> Vec<String> {
+        let (ip, any) = match self.family {
+            Family::V4 => ("ip", "0.0.0.0/0"),
+            Family::V6 => ("ip -6", "::/0"),
+        };
+        let mut rule = format!(
+            "{} rule {} fwmark {} table {}",
+            ip, verb, self.mark, self.table
+        );
+        if let Some(priority) = self.priority {
+            rule.push_str(&format!(" priority {}", priority));
+        }
+        let route = format!(
+            "{} route {} local {} dev lo table {}",
+            ip, verb, any, self.table
+        );
+        vec![rule, route]
+    }
+
+    fn address_family(&self) -> AddressFamily {
+        match self.family {
+            Family::V4 => AddressFamily::Inet,
+            Family::V6 => AddressFamily::Inet6,
+        }
+    }
+
+    fn header_table(&self) -> u8 {
+        // Tables above 255 only fit in the RTA_TABLE/FRA_TABLE attribute.
+        u8::try_from(self.table).unwrap_or(0)
+    }
+
+    fn rule_message(&self) -> RuleMessage {
+        let mut message = RuleMessage::default();
+        message.header = RuleHeader {
+            family: self.address_family(),
+            table: self.header_table(),
+            action: RuleAction::ToTable,
+            ..Default::default()
+        };
+        message.attributes = vec![
+            RuleAttribute::FwMark(self.mark.value),
+            RuleAttribute::FwMask(self.mark.mask),
+            RuleAttribute::Table(self.table),
+        ];
+        if let Some(priority) = self.priority {
+            message.attributes.push(RuleAttribute::Priority(priority));
+        }
+        message
+    }
+
+    fn route_message(&self) -> Result<RouteMessage> {
+        let lo = nix::net::if_::if_nametoindex("lo")?;
+        let mut message = RouteMessage::default();
+        message.header = RouteHeader {
+            address_family: self.address_family(),
+            table: self.header_table(),
+            protocol: RouteProtocol::Boot,
+            scope: RouteScope::Host,
+            kind: RouteType::Local,
+            ..Default::default()
+        };
+        message.attributes = vec![RouteAttribute::Table(self.table), RouteAttribute::Oif(lo)];
+        Ok(message)
+    }
+
+    fn is_rule(&self, rule: &RuleMessage) -> bool {
+        rule.header.family == self.address_family()
+            && rule
+                .attributes
+                .contains(&RuleAttribute::FwMark(self.mark.value))
+            && rule
+                .attributes
+                .contains(&RuleAttribute::FwMask(self.mark.mask))
+            && rule.attributes.contains(&RuleAttribute::Table(self.table))
+    }
+
+    fn is_route(&self, route: &RouteMessage) -> bool {
+        route.header.address_family == self.address_family()
+            && route.header.kind == RouteType::Local
+            && route.header.destination_prefix_length == 0
+            && route
+                .attributes
+                .contains(&RouteAttribute::Table(self.table))
+    }
+}
+
+/// Routing table given on the command line, a single id or an inclusive range like `100-199`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl TableRange {
+    /// The first table of the range that no routing rule points to yet.
+    pub fn pick(&self, netlink: &mut Netlink) -> Result<u32> {
+        let used = netlink.rule_ids()?;
+        (self.first..=self.last)
+            .find(|table| !used.contains(table))
+            .ok_or_else(|| eyre!("all routing tables {}-{} are in use", self.first, self.last))
+    }
+}
+
+impl FromStr for TableRange {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let parse = |n: &str| {
+            n.parse::<u32>()
+                .map_err(|e| eyre!("invalid routing table `{}`: {}", s, e))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first == 0 || first > last || (first..=last).all(|t| (253..=255).contains(&t)) {
+            return Err(eyre!("invalid routing table range `{}`", s));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+fn parse_messages(buf: &[u8]) -> Vec<NetlinkMessage<RouteNetlinkMessage>> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        match NetlinkMessage::<RouteNetlinkMessage>::deserialize(&buf[offset..]) {
+            Ok(message) => {
+                let len = message.header.length as usize;
+                messages.push(message);
+                if len == 0 {
+                    break;
+                }
+                offset += (len + 3) & !3;
+            }
+            Err(e) => {
+                tracing::warn!("failed to parse netlink message. error: {}", e);
+                break;
+            }
+        }
+    }
+    messages
+}
+
+pub struct Netlink {
+    socket: Socket,
+    sequence_number: u32,
+}
+
+impl Netlink {
+    pub fn new() -> Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Self {
+            socket,
+            sequence_number: 0,
+        })
+    }
+
+    fn send(&mut self, message: RouteNetlinkMessage, flags: u16) -> Result<()> {
+        self.sequence_number += 1;
+        let mut header = NetlinkHeader::default();
+        header.flags = NLM_F_REQUEST | flags;
+        header.sequence_number = self.sequence_number;
+        let mut packet = NetlinkMessage::new(header, NetlinkPayload::InnerMessage(message));
+        packet.finalize();
+        let mut buf = vec![0; packet.buffer_len()];
+        packet.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+        Ok(())
+    }
+
+    /// Sends one request and waits for the kernel to acknowledge it.
+    fn request(&mut self, message: RouteNetlinkMessage, flags: u16) -> Result<()> {
+        self.send(message, NLM_F_ACK | flags)?;
+        loop {
+            let (buf, _) = self.socket.recv_from_full()?;
+            for reply in parse_messages(&buf) {
+                if reply.header.sequence_number != self.sequence_number {
+                    continue;
+                }
+                if let NetlinkPayload::Error(e) = reply.payload {
+                    return match e.code {
+                        None => Ok(()),
+                        Some(_) => Err(e.to_io().into()),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Sends a dump request and collects the messages of the reply.
+    fn dump(&mut self, message: RouteNetlinkMessage) -> Result<Vec<RouteNetlinkMessage>> {
+        self.send(message, NLM_F_DUMP)?;
+        let mut messages = Vec::new();
+        loop {
+            let (buf, _) = self.socket.recv_from_full()?;
+            for reply in parse_messages(&buf) {
+                if reply.header.sequence_number != self.sequence_number {
+                    continue;
+                }
+                match reply.payload {
+                    NetlinkPayload::Done(_) => return Ok(messages),
+                    NetlinkPayload::Error(e) if e.code.is_some() => return Err(e.to_io().into()),
+                    NetlinkPayload::InnerMessage(message) => messages.push(message),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Every fwmark and table referenced by the routing rules of any address family.
+    pub fn rule_ids(&mut self) -> Result<Vec<u32>> {
+        let messages = self
+            .dump(RouteNetlinkMessage::GetRule(RuleMessage::default()))
+            .map_err(|e| e.wrap_err("failed to list routing rules"))?;
+        let mut ids = Vec::new();
+        for message in messages {
+            if let RouteNetlinkMessage::NewRule(rule) = message {
+                for attribute in rule.attributes {
+                    match attribute {
+                        RuleAttribute::FwMark(id) | RuleAttribute::Table(id) => ids.push(id),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// How many `local` routes the `local` table has, of any address family. The kernel adds one
+    /// for every address of the host, tproxy'd packets are only delivered if it has some.
+    pub fn local_routes(&mut self) -> Result<usize> {
+        let messages = self
+            .dump(RouteNetlinkMessage::GetRoute(RouteMessage::default()))
+            .map_err(|e| e.wrap_err("failed to list routes"))?;
+        Ok(messages
+            .iter()
+            .filter(|message| match message {
+                RouteNetlinkMessage::NewRoute(route) => {
+                    route.header.kind == RouteType::Local && route.header.table == RT_TABLE_LOCAL
+                }
+                _ => false,
+            })
+            .count())
+    }
+
+    /// The rules and routes of `routes` the kernel doesn't have.
+    pub fn missing(&mut self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+        let rules = self
+            .dump(RouteNetlinkMessage::GetRule(RuleMessage::default()))
+            .map_err(|e| e.wrap_err("failed to list routing rules"))?;
+        let kernel_routes = self
+            .dump(RouteNetlinkMessage::GetRoute(RouteMessage::default()))
+            .map_err(|e| e.wrap_err("failed to list routes"))?;
+        let mut missing = Vec::new();
+        for route in routes {
+            let has_rule = rules.iter().any(|message| {
+                matches!(message, RouteNetlinkMessage::NewRule(rule) if route.is_rule(rule))
+            });
+            if !has_rule {
+                missing.push((Removed::Rule, *route));
+            }
+            let has_route = kernel_routes.iter().any(
+                |message| matches!(message, RouteNetlinkMessage::NewRoute(r) if route.is_route(r)),
+            );
+            if !has_route {
+                missing.push((Removed::Route, *route));
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn add_rule(&mut self, route: &PolicyRoute) -> Result<()> {
+        self.request(
+            RouteNetlinkMessage::NewRule(route.rule_message()),
+            NLM_F_CREATE | NLM_F_EXCL,
+        )
+        .map_err(|e| e.wrap_err(format!("failed to add routing rule {:?}", route)))
+    }
+
+    pub fn delete_rule(&mut self, route: &PolicyRoute) -> Result<()> {
+        self.request(RouteNetlinkMessage::DelRule(route.rule_message()), 0)
+            .map_err(|e| e.wrap_err(format!("failed to delete routing rule {:?}", route)))
+    }
+
+    pub fn add_route(&mut self, route: &PolicyRoute) -> Result<()> {
+        self.request(
+            RouteNetlinkMessage::NewRoute(route.route_message()?),
+            NLM_F_CREATE | NLM_F_EXCL,
+        )
+        .map_err(|e| e.wrap_err(format!("failed to add local route {:?}", route)))
+    }
+
+    pub fn delete_route(&mut self, route: &PolicyRoute) -> Result<()> {
+        self.request(RouteNetlinkMessage::DelRoute(route.route_message()?), 0)
+            .map_err(|e| e.wrap_err(format!("failed to delete local route {:?}", route)))
+    }
+}
+
+/// What disappeared from the kernel, as reported by a [`Monitor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Removed {
+    Rule,
+    Route,
+}
+
+/// Subscription to rule and route deletions.
+pub struct Monitor {
+    socket: Socket,
+}
+
+impl Monitor {
+    /// `timeout` bounds how long [`Monitor::next`] blocks, so the caller can check for shutdown.
+    pub fn new(timeout: Duration) -> Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        for group in [
+            RTNLGRP_IPV4_RULE,
+            RTNLGRP_IPV4_ROUTE,
+            RTNLGRP_IPV6_RULE,
+            RTNLGRP_IPV6_ROUTE,
+        ] {
+            socket.add_membership(group)?;
+        }
+        let timeout =
+            nix::sys::time::TimeVal::new(timeout.as_secs() as _, timeout.subsec_micros() as _);
+        nix::sys::socket::setsockopt(&socket, nix::sys::socket::sockopt::ReceiveTimeout, &timeout)?;
+        Ok(Self { socket })
+    }
+
+    /// Waits for the rule or route of any of `routes` to be deleted. Returns nothing on timeout.
+    pub fn next(&self, routes: &[PolicyRoute]) -> Result<Vec<(Removed, PolicyRoute)>> {
+        let buf = match self.socket.recv_from_full() {
+            Ok((buf, _)) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(e) => return Err(eyre!("failed to read netlink notification: {}", e)),
+        };
+        let mut removed = Vec::new();
+        for message in parse_messages(&buf) {
+            for route in routes {
+                match &message.payload {
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRule(rule))
+                        if route.is_rule(rule) =>
+                    {
+                        removed.push((Removed::Rule, *route));
+                    }
+                    NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(r))
+                        if route.is_route(r) =>
+                    {
+                        removed.push((Removed::Route, *route));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
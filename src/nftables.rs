@@ -0,0 +1,202 @@
+use crate::backend::pipe_to;
+use crate::rules::{
+    CgroupMatch, Chain, Counters, Family, Hook, Match, Rule, RuleSet, Table, Target,
+};
+use eyre::Result;
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+
+/// `path` is relative to the mount point of the unified hierarchy, see
+/// [`CgroupSpec::for_path`](crate::plan::CgroupSpec::for_path).
+fn cgroupv2_expr(path: &str) -> String {
+    let path = path.trim_matches('/');
+    let level = path.split('/').filter(|c| !c.is_empty()).count();
+    format!("socket cgroupv2 level {} \"{}\"", level, path)
+}
+
+fn base_chain(chain: &Chain) -> String {
+    let (kind, hook, priority) = match (chain.table, chain.hook) {
+        (Table::Nat, Hook::Output) => ("nat", "output", -100),
+        (Table::Nat, Hook::Prerouting) => ("nat", "prerouting", -100),
+        (Table::Mangle, Hook::Output) => ("route", "output", -150),
+        (Table::Mangle, Hook::Prerouting) => ("filter", "prerouting", -150),
+        (Table::Raw, Hook::Output) => ("filter", "output", -300),
+        (Table::Raw, Hook::Prerouting) => ("filter", "prerouting", -300),
+        (Table::Filter, Hook::Output) => ("filter", "output", 0),
+        (Table::Filter, Hook::Prerouting) => ("filter", "prerouting", 0),
+    };
+    format!("type {} hook {} priority {};", kind, hook, priority)
+}
+
+/// Chains of one session live in the same table, so name them after where they are attached.
+fn chain_name(chain: &Chain) -> String {
+    let suffix = match chain.family {
+        Family::V4 => "",
+        Family::V6 => "6",
+    };
+    format!(
+        "{}_{}{}",
+        chain.table,
+        chain.hook.to_string().to_lowercase(),
+        suffix
+    )
+}
+
+fn rule_expr(family: Family, rule: &Rule) -> String {
+    let (nfproto, ip) = match family {
+        Family::V4 => ("ipv4", "ip"),
+        Family::V6 => ("ipv6", "ip6"),
+    };
+    let mut parts = vec![format!("meta nfproto {}", nfproto)];
+    for m in &rule.matches {
+        parts.push(match m {
+            Match::Protocol(proto) => format!("meta l4proto {}", proto),
+            Match::OutInterface(iface) => format!("oifname \"{}\"", iface),
+            Match::Cgroup(CgroupMatch::Path(path)) => cgroupv2_expr(path),
+            Match::Cgroup(CgroupMatch::ClassId(class_id)) => format!("meta cgroup {}", class_id),
+            Match::Mark(mark) if mark.mask == u32::MAX => format!("meta mark {}", mark.value),
+            Match::Mark(mark) => format!("meta mark and {:#x} == {:#x}", mark.mask, mark.value),
+            Match::DstPort(port) => format!("th dport {}", port),
+            Match::DstNet(nets) => {
+                let nets: Vec<String> = nets.iter().map(|n| n.to_string()).collect();
+                format!("{} daddr {{ {} }}", ip, nets.join(", "))
+            }
+            Match::DstPorts(ports) => {
+                let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+                format!("th dport {{ {} }}", ports.join(", "))
+            }
+            Match::DstSet(name) => format!("{} daddr @{}", ip, name),
+            Match::Nth { every, packet } => format!("numgen inc mod {} == {}", every, packet),
+        });
+    }
+    if rule.target != Target::Return {
+        parts.push("counter".to_owned());
+    }
+    parts.push(match &rule.target {
+        Target::Return => "return".to_owned(),
+        Target::Redirect { port } => format!("redirect to :{}", port),
+        Target::TProxy { ip: addr, port } => {
+            format!("tproxy {} to {}", ip, SocketAddr::new(*addr, *port as u16))
+        }
+        Target::SetMark(mark) if mark.mask == u32::MAX => format!("meta mark set {}", mark.value),
+        Target::SetMark(mark) => format!(
+            "meta mark set meta mark and {:#x} or {:#x}",
+            !mark.mask, mark.value
+        ),
+        Target::Dnat(destination) => format!("dnat {} to {}", ip, destination),
+        Target::Log => "log".to_owned(),
+        Target::Reject => "reject".to_owned(),
+        Target::Drop => "drop".to_owned(),
+    });
+    parts.join(" ")
+}
+
+/// Renders the rule set as one `inet` table for `nft -f`.
+pub fn script(rules: &RuleSet) -> String {
+    let mut script = String::new();
+    writeln!(script, "table inet {} {{", rules.name).unwrap();
+    for set in &rules.sets {
+        let kind = match set.family {
+            Family::V4 => "ipv4_addr",
+            Family::V6 => "ipv6_addr",
+        };
+        writeln!(script, "  set {} {{", set.name).unwrap();
+        if set.timeout {
+            writeln!(script, "    type {}; flags timeout;", kind).unwrap();
+        } else {
+            writeln!(script, "    type {}; flags interval; auto-merge;", kind).unwrap();
+        }
+        if !set.nets.is_empty() {
+            let nets: Vec<String> = set.nets.iter().map(|n| n.to_string()).collect();
+            writeln!(script, "    elements = {{ {} }}", nets.join(", ")).unwrap();
+        }
+        writeln!(script, "  }}").unwrap();
+    }
+    for chain in &rules.chains {
+        writeln!(script, "  chain {} {{", chain_name(chain)).unwrap();
+        writeln!(script, "    {}", base_chain(chain)).unwrap();
+        for rule in &chain.rules {
+            writeln!(script, "    {}", rule_expr(chain.family, rule)).unwrap();
+        }
+        writeln!(script, "  }}").unwrap();
+    }
+    writeln!(script, "}}").unwrap();
+    script
+}
+
+pub fn install(rules: &RuleSet) -> Result<()> {
+    let script = script(rules);
+    tracing::debug!("applying nftables ruleset:\n{}", script);
+    // `nft -f` applies the whole file as one transaction, so a rejected rule leaves nothing behind.
+    pipe_to("nft", &["-f", "-"], &script)
+}
+
+/// Shell command doing what [`install`] does.
+pub fn install_command(rules: &RuleSet) -> String {
+    format!("nft -f - <<'EOF'\n{}EOF", script(rules))
+}
+
+/// Shell command doing what [`uninstall`] does.
+pub fn uninstall_command(rules: &RuleSet) -> String {
+    format!("nft delete table inet {}", rules.name)
+}
+
+/// Names of the `inet` tables currently in the kernel that start with `prefix`.
+pub fn list_tables(prefix: &str) -> Result<Vec<String>> {
+    let tables = cmd_lib::run_fun! { nft list tables }?;
+    Ok(tables
+        .lines()
+        .filter_map(|line| line.strip_prefix("table inet "))
+        .map(str::trim)
+        .filter(|name| name.starts_with(prefix))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Counters of every chain in `rules`, in the same order.
+pub fn counters(rules: &RuleSet) -> Result<Vec<Counters>> {
+    let name = &rules.name;
+    let listing = cmd_lib::run_fun! { nft list table inet ${name} }?;
+    let mut counters = vec![Counters::default(); rules.chains.len()];
+    let mut current = None;
+    for line in listing.lines().map(str::trim) {
+        if let Some(chain) = line.strip_prefix("chain ") {
+            let chain = chain.trim_end_matches('{').trim();
+            current = rules.chains.iter().position(|c| chain_name(c) == chain);
+            continue;
+        }
+        let (index, counted) = match (current, line.split_once("counter packets ")) {
+            (Some(index), Some((_, counted))) => (index, counted),
+            _ => continue,
+        };
+        let words: Vec<&str> = counted.split_whitespace().collect();
+        if let [packets, "bytes", bytes, ..] = words[..] {
+            counters[index].packets += packets.parse::<u64>().unwrap_or(0);
+            counters[index].bytes += bytes.parse::<u64>().unwrap_or(0);
+        }
+    }
+    Ok(counters)
+}
+
+/// Adds `ip` to the set `set` of `rules` for `timeout` seconds, or renews it. Adding an element
+/// again keeps its old timeout, so it is deleted and added back in the same transaction.
+pub fn add_to_set(rules: &RuleSet, set: &str, ip: IpAddr, timeout: u32) -> Result<()> {
+    let element = format!("inet {} {} {{ {} }}", rules.name, set, ip);
+    let timed = format!(
+        "inet {} {} {{ {} timeout {}s }}",
+        rules.name, set, ip, timeout
+    );
+    let script = format!(
+        "add element {}\ndelete element {}\nadd element {}\n",
+        timed, element, timed
+    );
+    pipe_to("nft", &["-f", "-"], &script)
+}
+
+pub fn uninstall(rules: &RuleSet) -> Result<()> {
+    let name = &rules.name;
+    (cmd_lib::run_cmd! {
+        nft delete table inet ${name};
+    })?;
+    Ok(())
+}
@@ -0,0 +1,920 @@
+//! What a session sets up, computed without changing anything in the kernel.
+//!
+//! The guards install exactly these rules and routes, and `--dry-run` prints them instead.
+
+use crate::backend::Backend;
+use crate::bpf;
+use crate::class_id;
+use crate::dns;
+use crate::guards::{cgroup2_mount, SessionOptions, UdpPolicy};
+use crate::netlink::{Netlink, PolicyRoute};
+use crate::rules::{
+    CgroupMatch, Chain, Cidr, Destination, Family, Hook, IpSet, Mark, Match, PortRange, Protocol,
+    RuleSet, Table, Target,
+};
+use eyre::{eyre, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// The cgroup of a session, before it is created.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CgroupSpec {
+    pub path: String,
+    pub class_id: u32,
+    pub hier_v2: bool,
+}
+
+impl CgroupSpec {
+    /// A new `cproxy-<class id>` cgroup for a process. `class_id` turns the id the session would
+    /// like into the one it gets, see [`class_id::allocate`].
+    pub fn for_pid(pid: u32, class_id: impl FnOnce(u32) -> Result<u32>) -> Result<Self> {
+        let class_id = class_id(pid)?;
+        Ok(Self {
+            path: format!("cproxy-{}", class_id),
+            class_id,
+            hier_v2: cgroups_rs::hierarchies::auto().v2(),
+        })
+    }
+
+    /// A cgroup given on the command line. In the unified hierarchy either a directory below its
+    /// mount point or a path relative to it, which is what is kept.
+    pub fn for_path(path: &str, class_id: impl FnOnce(u32) -> Result<u32>) -> Result<Self> {
+        // Use path hash as class_id to avoid conflicts
+        let class_id = class_id(class_id::fnv1a(path.as_bytes()))?;
+        let hier_v2 = cgroups_rs::hierarchies::auto().v2();
+        Ok(Self {
+            path: if hier_v2 {
+                v2_relative(path)?
+            } else {
+                path.to_owned()
+            },
+            class_id,
+            hier_v2,
+        })
+    }
+
+    pub fn cgroup_match(&self) -> CgroupMatch {
+        if self.hier_v2 {
+            CgroupMatch::Path(self.path.clone())
+        } else {
+            CgroupMatch::ClassId(self.class_id)
+        }
+    }
+
+    /// Name shared by everything a session installs, e.g. its nftables table.
+    pub fn session_name(&self) -> String {
+        format!("cproxy_{}", self.class_id)
+    }
+
+    /// Directory of the cgroup in the unified hierarchy.
+    pub fn v2_dir(&self) -> Result<PathBuf> {
+        let mount = cgroup2_mount()?;
+        let path = Path::new(&self.path);
+        let relative = path.strip_prefix(&mount).unwrap_or(path);
+        let relative = relative.strip_prefix("/").unwrap_or(relative);
+        Ok(mount.join(relative))
+    }
+
+    /// Directory of the cgroup in the unified hierarchy, or in the net_cls one on cgroup v1.
+    pub fn dir(&self) -> Result<PathBuf> {
+        if self.hier_v2 {
+            self.v2_dir()
+        } else {
+            let root = cgroups_rs::hierarchies::auto().root();
+            Ok(root.join("net_cls").join(self.path.trim_start_matches('/')))
+        }
+    }
+}
+
+/// `path` relative to the mount point of the unified hierarchy, `socket cgroupv2` and
+/// `-m cgroup --path` don't know where it is mounted.
+fn v2_relative(path: &str) -> Result<String> {
+    let path = Path::new(path);
+    if path.is_relative() {
+        return Ok(path.display().to_string());
+    }
+    let mount = cgroup2_mount()?;
+    match path.strip_prefix(&mount) {
+        Ok(relative) => Ok(relative.display().to_string()),
+        Err(_) => Err(eyre!(
+            "cgroup {} is not in the unified hierarchy mounted at {}",
+            path.display(),
+            mount.display()
+        )),
+    }
+}
+
+/// Chain name prefix of each mode.
+const CHAIN_PREFIXES: [(&str, &str); 4] = [
+    ("redirect", "rd"),
+    ("tproxy", "tp"),
+    ("trace", "tr"),
+    ("block", "bl"),
+];
+
+/// Names of the output and prerouting chains of a session.
+pub fn chain_names(mode: &str, class_id: u32) -> (String, String) {
+    let prefix = CHAIN_PREFIXES
+        .iter()
+        .find(|(m, _)| *m == mode)
+        .map_or("rd", |(_, prefix)| prefix);
+    (
+        format!("cp_{}_out_{}", prefix, class_id),
+        format!("cp_{}_pre_{}", prefix, class_id),
+    )
+}
+
+/// A class id as it ends the names of a session's objects, digits only.
+fn parse_id(s: &str) -> Option<u32> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Class id of the session a chain named by [`chain_names`] belongs to. Other tools' chains
+/// may start with `cp_` too, so the whole name has to match.
+pub fn chain_owner(name: &str) -> Option<u32> {
+    let (prefix, rest) = name.strip_prefix("cp_")?.split_once('_')?;
+    let (hook, id) = rest.split_once('_')?;
+    if !CHAIN_PREFIXES.iter().any(|(_, p)| *p == prefix) || !["out", "pre"].contains(&hook) {
+        return None;
+    }
+    parse_id(id)
+}
+
+/// Class id of the session of a set named by [`set_name`], like [`chain_owner`].
+pub fn set_owner(name: &str) -> Option<u32> {
+    let (kind, id) = name.strip_prefix("cp_")?.split_once('_')?;
+    let kind = kind.strip_suffix('6').unwrap_or(kind);
+    if !SET_KINDS.contains(&kind) {
+        return None;
+    }
+    parse_id(id)
+}
+
+/// Class id of the session of an nftables table or bpf pin directory named by
+/// [`CgroupSpec::session_name`].
+pub fn session_owner(name: &str) -> Option<u32> {
+    parse_id(name.strip_prefix("cproxy_")?)
+}
+
+/// Class id of the session of a cgroup created by [`CgroupSpec::for_pid`].
+pub fn cgroup_owner(name: &str) -> Option<u32> {
+    parse_id(name.strip_prefix("cproxy-")?)
+}
+
+/// Ranges bypassed unless `--no-default-bypass` is given: private (RFC 1918 and unique local),
+/// CGNAT, link-local and multicast destinations.
+pub const DEFAULT_BYPASS: [&str; 9] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "100.64.0.0/10",
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// The ranges of `family` in `nets`, without those inside other ranges. nftables refuses
+/// overlapping set elements.
+fn outermost(nets: &[Cidr], family: Family) -> Vec<Cidr> {
+    let mut outermost: Vec<Cidr> = Vec::new();
+    for net in nets.iter().filter(|n| n.family() == family) {
+        if outermost.iter().any(|n| n.contains(net)) {
+            continue;
+        }
+        outermost.retain(|n| !net.contains(n));
+        outermost.push(*net);
+    }
+    outermost
+}
+
+/// `ports` sorted, with overlapping and adjacent ranges merged.
+pub fn merge_ports(ports: &[PortRange]) -> Vec<PortRange> {
+    let mut sorted = ports.to_vec();
+    sorted.sort();
+    let mut merged: Vec<PortRange> = Vec::new();
+    for ports in sorted {
+        match merged.last_mut() {
+            Some(last) if ports.start as u32 <= last.end as u32 + 1 => {
+                last.end = last.end.max(ports.end)
+            }
+            _ => merged.push(ports),
+        }
+    }
+    merged
+}
+
+/// Kinds of kernel sets a session may have.
+const SET_KINDS: [&str; 3] = ["bypass", "only", "domains"];
+
+fn set_name(kind: &str, family: Family, class_id: u32) -> String {
+    let suffix = match family {
+        Family::V4 => "",
+        Family::V6 => "6",
+    };
+    format!("cp_{}{}_{}", kind, suffix, class_id)
+}
+
+/// Kernel set with the `kind` ranges of `family`, if any of them came from a file. The ranges
+/// given on the command line go into the set as well, so one match covers them all.
+fn dst_set(
+    kind: &str,
+    nets: &[Cidr],
+    from_files: &[Cidr],
+    family: Family,
+    class_id: u32,
+) -> Option<IpSet> {
+    if !from_files.iter().any(|n| n.family() == family) {
+        return None;
+    }
+    Some(IpSet {
+        name: set_name(kind, family, class_id),
+        family,
+        nets: nets
+            .iter()
+            .chain(from_files)
+            .filter(|n| n.family() == family)
+            .copied()
+            .collect(),
+        timeout: false,
+    })
+}
+
+/// Matches destinations in `nets` and `from_files` of `family`, `None` if there are none.
+fn dst_match(
+    kind: &str,
+    nets: &[Cidr],
+    from_files: &[Cidr],
+    family: Family,
+    class_id: u32,
+) -> Option<Match> {
+    if let Some(set) = dst_set(kind, nets, from_files, family, class_id) {
+        return Some(Match::DstSet(set.name));
+    }
+    let nets = outermost(nets, family);
+    if nets.is_empty() {
+        None
+    } else {
+        Some(Match::DstNet(nets))
+    }
+}
+
+/// The sets [`bypass_rule`] and [`only_matches`] refer to.
+fn dst_sets(options: &SessionOptions, class_id: u32) -> Vec<IpSet> {
+    let mut bypass_families = options.families();
+    if options.block_ipv6 && !options.ipv6 {
+        bypass_families.push(Family::V6);
+    }
+    let bypass = bypass_families.into_iter().filter_map(|family| {
+        dst_set(
+            "bypass",
+            &options.bypass,
+            &options.bypass_files,
+            family,
+            class_id,
+        )
+    });
+    let only = options.families().into_iter().filter_map(|family| {
+        dst_set(
+            "only",
+            &options.only_dst,
+            &options.only_dst_files,
+            family,
+            class_id,
+        )
+    });
+    let domains = options
+        .families()
+        .into_iter()
+        .filter(|_| !options.proxy_domains.is_empty())
+        .map(|family| IpSet {
+            name: set_name("domains", family, class_id),
+            family,
+            nets: Vec::new(),
+            timeout: true,
+        });
+    bypass.chain(only).chain(domains).collect()
+}
+
+/// The `RETURN` rule for bypassed destinations of `family`, if there are any.
+fn bypass_rule(
+    family: Family,
+    options: &SessionOptions,
+    class_id: u32,
+) -> Option<(Vec<Match>, Target)> {
+    let bypass = dst_match(
+        "bypass",
+        &options.bypass,
+        &options.bypass_files,
+        family,
+        class_id,
+    )?;
+    Some((vec![bypass], Target::Return))
+}
+
+/// What narrows the catch-all proxy rules down to `--only-dst` and `--only-dport`. `None` if no
+/// traffic of `family` is proxied at all.
+fn only_matches(family: Family, options: &SessionOptions, class_id: u32) -> Option<Vec<Match>> {
+    let mut matches = Vec::new();
+    if !options.only_dst.is_empty() || !options.only_dst_files.is_empty() {
+        matches.push(dst_match(
+            "only",
+            &options.only_dst,
+            &options.only_dst_files,
+            family,
+            class_id,
+        )?);
+    }
+    if !options.only_dport.is_empty() {
+        matches.push(Match::DstPorts(merge_ports(&options.only_dport)));
+    }
+    if !options.proxy_domains.is_empty() {
+        matches.push(Match::DstSet(set_name("domains", family, class_id)));
+    }
+    Some(matches)
+}
+
+/// Queries of the `--proxy-domain` forwarder, see [`dns`].
+fn forwarder_match() -> Match {
+    Match::Mark(Mark::new(dns::MARK))
+}
+
+/// Sends the session's DNS queries to its `--proxy-domain` forwarder, at the start of a nat chain
+/// so queries to a resolver on loopback are caught too. With `proxy_port` the forwarder's own
+/// queries go to the proxy, otherwise they go out directly.
+fn snoop_rules(
+    chain: Chain,
+    cgroup: &Match,
+    options: &SessionOptions,
+    proxy_port: Option<u32>,
+) -> Chain {
+    let mut chain = chain;
+    if let Some(port) = proxy_port {
+        chain = chain.rule(
+            vec![
+                Match::Protocol(Protocol::Udp),
+                forwarder_match(),
+                Match::DstPort(53),
+            ],
+            Target::Redirect { port },
+        );
+    }
+    chain.rule(vec![forwarder_match()], Target::Return).rule(
+        vec![
+            Match::Protocol(Protocol::Udp),
+            cgroup.clone(),
+            Match::DstPort(53),
+        ],
+        Target::Redirect {
+            port: options.snoop_port as u32,
+        },
+    )
+}
+
+/// The `--override-dns` servers of `family`, a server can only replace destinations of its own
+/// address family.
+fn override_servers(family: Family, options: &SessionOptions) -> Vec<Destination> {
+    options
+        .override_dns
+        .iter()
+        .filter(|d| Family::of(&d.ip) == family)
+        .copied()
+        .collect()
+}
+
+/// Sends the session's DNS to `servers`. With several, each takes every n-th connection: the
+/// first one of n, the next one of the n - 1 left, and so on.
+fn override_dns_rules(chain: Chain, cgroup: &Match, servers: &[Destination]) -> Chain {
+    let mut chain = chain;
+    for proto in [Protocol::Udp, Protocol::Tcp] {
+        for (i, server) in servers.iter().enumerate() {
+            let mut matches = vec![Match::Protocol(proto), cgroup.clone(), Match::DstPort(53)];
+            let left = (servers.len() - i) as u32;
+            if left > 1 {
+                matches.push(Match::Nth {
+                    every: left,
+                    packet: 0,
+                });
+            }
+            chain = chain.rule(matches, Target::Dnat(*server));
+        }
+    }
+    chain
+}
+
+/// IPv6 kill switch: rejects everything the session sends over IPv6 except to loopback and
+/// bypassed destinations.
+fn ipv6_kill_switch(
+    chain_name: &str,
+    cgroup: &Match,
+    options: &SessionOptions,
+    class_id: u32,
+) -> Chain {
+    let mut chain = Chain::new(chain_name, Family::V6, Table::Filter, Hook::Output)
+        .rule(vec![Match::OutInterface("lo".into())], Target::Return);
+    if !options.proxy_domains.is_empty() {
+        chain = chain.rule(vec![forwarder_match()], Target::Return);
+    }
+    if let Some((matches, target)) = bypass_rule(Family::V6, options, class_id) {
+        chain = chain.rule(matches, target);
+    }
+    chain.rule(vec![cgroup.clone()], Target::Reject)
+}
+
+pub fn redirect_rules(
+    port: u32,
+    output_chain_name: &str,
+    cgroup: &CgroupSpec,
+    options: &SessionOptions,
+) -> RuleSet {
+    let cgroup_match = Match::Cgroup(cgroup.cgroup_match());
+    let mut rules = RuleSet::new(&cgroup.session_name());
+    rules.sets = dst_sets(options, cgroup.class_id);
+    let snooping = !options.proxy_domains.is_empty();
+    for family in options.families() {
+        let mut output_chain = Chain::new(output_chain_name, family, Table::Nat, Hook::Output);
+        if snooping {
+            let proxy_port = Some(options.dns_port(port)).filter(|_| options.redirect_dns);
+            output_chain = snoop_rules(output_chain, &cgroup_match, options, proxy_port);
+        }
+        // DNS goes to the servers or the proxy even if the resolver is in a bypassed range. When
+        // snooping, the forwarder gets the UDP queries instead, DNS over TCP isn't snooped.
+        let override_dns = if snooping {
+            Vec::new()
+        } else {
+            override_servers(family, options)
+        };
+        // Before the loopback returns, so queries to a stub resolver like systemd-resolved's
+        // `127.0.0.53` go to the proxy too. DNAT can't take those off loopback to the
+        // `--override-dns` servers, the program gets its own resolv.conf for that instead.
+        if override_dns.is_empty() && options.redirect_dns {
+            let protos: &[Protocol] = if snooping {
+                &[Protocol::Tcp]
+            } else {
+                &[Protocol::Udp, Protocol::Tcp]
+            };
+            for &proto in protos {
+                output_chain = output_chain.rule(
+                    vec![
+                        Match::Protocol(proto),
+                        cgroup_match.clone(),
+                        Match::DstPort(53),
+                    ],
+                    Target::Redirect {
+                        port: options.dns_port(port),
+                    },
+                );
+            }
+        }
+        output_chain = output_chain
+            .rule(
+                vec![
+                    Match::Protocol(Protocol::Udp),
+                    Match::OutInterface("lo".into()),
+                ],
+                Target::Return,
+            )
+            .rule(
+                vec![
+                    Match::Protocol(Protocol::Tcp),
+                    Match::OutInterface("lo".into()),
+                ],
+                Target::Return,
+            );
+        if !override_dns.is_empty() {
+            output_chain = override_dns_rules(output_chain, &cgroup_match, &override_dns);
+        }
+        if let Some((matches, target)) = bypass_rule(family, options, cgroup.class_id) {
+            output_chain = output_chain.rule(matches, target);
+        }
+        if let Some(only) = only_matches(family, options, cgroup.class_id) {
+            let mut matches = vec![Match::Protocol(Protocol::Tcp), cgroup_match.clone()];
+            matches.extend(only);
+            for mapping in &options.port_map {
+                let mut matches = matches.clone();
+                matches.push(Match::DstPorts(merge_ports(&mapping.dports)));
+                output_chain = output_chain.rule(
+                    matches,
+                    Target::Redirect {
+                        port: mapping.port as u32,
+                    },
+                );
+            }
+            output_chain = output_chain.rule(
+                matches,
+                Target::Redirect {
+                    port: options.tcp_port(port),
+                },
+            );
+        }
+        rules = rules.chain(output_chain);
+    }
+    for family in options.families() {
+        if let Some(chain) = udp_policy_chain(
+            output_chain_name,
+            family,
+            &cgroup_match,
+            options,
+            cgroup.class_id,
+        ) {
+            rules = rules.chain(chain);
+        }
+    }
+    if options.block_ipv6 {
+        rules = rules.chain(ipv6_kill_switch(
+            output_chain_name,
+            &cgroup_match,
+            options,
+            cgroup.class_id,
+        ));
+    }
+    rules
+}
+
+/// Refuses the UDP redirect mode would proxy if it could, for `--udp-policy`. DNS and UDP that
+/// goes direct anyway, to bypassed or not `--only-dst` destinations, is let through.
+fn udp_policy_chain(
+    chain_name: &str,
+    family: Family,
+    cgroup: &Match,
+    options: &SessionOptions,
+    class_id: u32,
+) -> Option<Chain> {
+    let target = match options.udp_policy {
+        UdpPolicy::Direct => return None,
+        UdpPolicy::Reject => Target::Reject,
+        UdpPolicy::Drop => Target::Drop,
+    };
+    let only = only_matches(family, options, class_id)?;
+    let mut chain = Chain::new(chain_name, family, Table::Filter, Hook::Output)
+        .rule(
+            vec![
+                Match::Protocol(Protocol::Udp),
+                Match::OutInterface("lo".into()),
+            ],
+            Target::Return,
+        )
+        .rule(
+            vec![
+                Match::Protocol(Protocol::Udp),
+                cgroup.clone(),
+                Match::DstPort(53),
+            ],
+            Target::Return,
+        );
+    if let Some((matches, target)) = bypass_rule(family, options, class_id) {
+        chain = chain.rule(matches, target);
+    }
+    let mut matches = vec![Match::Protocol(Protocol::Udp), cgroup.clone()];
+    matches.extend(only);
+    Some(chain.rule(matches, target))
+}
+
+pub fn tproxy_rules(
+    port: u32,
+    mark: Mark,
+    output_chain_name: &str,
+    prerouting_chain_name: &str,
+    cgroup: &CgroupSpec,
+    options: &SessionOptions,
+) -> RuleSet {
+    let cgroup_match = Match::Cgroup(cgroup.cgroup_match());
+    let mut rules = RuleSet::new(&cgroup.session_name());
+    rules.sets = dst_sets(options, cgroup.class_id);
+    let snooping = !options.proxy_domains.is_empty();
+    for family in options.families() {
+        let mut prerouting_chain = Chain::new(
+            prerouting_chain_name,
+            family,
+            Table::Mangle,
+            Hook::Prerouting,
+        );
+        let mut output_chain = Chain::new(output_chain_name, family, Table::Mangle, Hook::Output);
+        let override_dns = if snooping {
+            Vec::new()
+        } else {
+            override_servers(family, options)
+        };
+        if snooping {
+            // Queries go to the forwarder through the nat chain below, and its own go out directly.
+            output_chain = output_chain
+                .rule(vec![forwarder_match()], Target::Return)
+                .rule(
+                    vec![
+                        Match::Protocol(Protocol::Udp),
+                        cgroup_match.clone(),
+                        Match::DstPort(53),
+                    ],
+                    Target::Return,
+                );
+        }
+        if !override_dns.is_empty() {
+            // Unmarked, so the nat chain below sends DNS straight to the server instead of TPROXY
+            // taking it to the proxy.
+            for proto in [Protocol::Udp, Protocol::Tcp] {
+                output_chain = output_chain.rule(
+                    vec![
+                        Match::Protocol(proto),
+                        cgroup_match.clone(),
+                        Match::DstPort(53),
+                    ],
+                    Target::Return,
+                );
+            }
+        }
+        let dns_port = options.dns_port(port);
+        let dns_mapped = options
+            .port_map
+            .iter()
+            .any(|m| m.dports.iter().any(|p| p.contains(53)));
+        for (proto, port) in [
+            (Protocol::Udp, options.udp_port(port)),
+            (Protocol::Tcp, options.tcp_port(port)),
+        ] {
+            if dns_port != port || dns_mapped {
+                prerouting_chain = prerouting_chain.rule(
+                    vec![
+                        Match::Protocol(proto),
+                        Match::Mark(mark),
+                        Match::DstPort(53),
+                    ],
+                    Target::TProxy {
+                        ip: family.localhost(),
+                        port: dns_port,
+                    },
+                );
+            }
+        }
+        for mapping in &options.port_map {
+            for proto in [Protocol::Udp, Protocol::Tcp] {
+                prerouting_chain = prerouting_chain.rule(
+                    vec![
+                        Match::Protocol(proto),
+                        Match::Mark(mark),
+                        Match::DstPorts(merge_ports(&mapping.dports)),
+                    ],
+                    Target::TProxy {
+                        ip: family.localhost(),
+                        port: mapping.port as u32,
+                    },
+                );
+            }
+        }
+        for (proto, port) in [
+            (Protocol::Udp, options.udp_port(port)),
+            (Protocol::Tcp, options.tcp_port(port)),
+        ] {
+            prerouting_chain = prerouting_chain.rule(
+                vec![Match::Protocol(proto), Match::Mark(mark)],
+                Target::TProxy {
+                    ip: family.localhost(),
+                    port,
+                },
+            );
+        }
+        for proto in [Protocol::Tcp, Protocol::Udp] {
+            output_chain = output_chain.rule(
+                vec![Match::Protocol(proto), Match::OutInterface("lo".into())],
+                Target::Return,
+            );
+        }
+        let only = only_matches(family, options, cgroup.class_id);
+        if let Some((matches, target)) = bypass_rule(family, options, cgroup.class_id) {
+            // Marking doesn't end the chain, so DNS stays marked after the bypass returns.
+            let dns_proxied = !snooping
+                && override_dns.is_empty()
+                && (options.only_dport.is_empty()
+                    || options.only_dport.iter().any(|p| p.contains(53)));
+            if let Some(only) = only.as_ref().filter(|_| dns_proxied) {
+                for proto in [Protocol::Udp, Protocol::Tcp] {
+                    let mut dns = vec![
+                        Match::Protocol(proto),
+                        cgroup_match.clone(),
+                        Match::DstPort(53),
+                    ];
+                    dns.extend(
+                        only.iter()
+                            .filter(|m| matches!(m, Match::DstNet(_) | Match::DstSet(_)))
+                            .cloned(),
+                    );
+                    output_chain = output_chain.rule(dns, Target::SetMark(mark));
+                }
+            }
+            output_chain = output_chain.rule(matches, target);
+        }
+        if let Some(only) = only {
+            for proto in [Protocol::Tcp, Protocol::Udp] {
+                let mut matches = vec![Match::Protocol(proto), cgroup_match.clone()];
+                matches.extend(only.iter().cloned());
+                output_chain = output_chain.rule(matches, Target::SetMark(mark));
+            }
+        }
+        rules = rules.chain(prerouting_chain).chain(output_chain);
+
+        // With `--proxy-domain` the forwarder asks the DNS server instead.
+        if snooping {
+            let dns_chain = Chain::new(output_chain_name, family, Table::Nat, Hook::Output);
+            rules = rules.chain(snoop_rules(dns_chain, &cgroup_match, options, None));
+        } else if !override_dns.is_empty() {
+            let mut dns_chain = Chain::new(output_chain_name, family, Table::Nat, Hook::Output);
+            for proto in [Protocol::Udp, Protocol::Tcp] {
+                dns_chain = dns_chain.rule(
+                    vec![Match::Protocol(proto), Match::OutInterface("lo".into())],
+                    Target::Return,
+                );
+            }
+            dns_chain = override_dns_rules(dns_chain, &cgroup_match, &override_dns);
+            rules = rules.chain(dns_chain);
+        }
+    }
+    if options.block_ipv6 {
+        rules = rules.chain(ipv6_kill_switch(
+            output_chain_name,
+            &cgroup_match,
+            options,
+            cgroup.class_id,
+        ));
+    }
+    rules
+}
+
+pub fn trace_rules(
+    output_chain_name: &str,
+    cgroup: &CgroupSpec,
+    options: &SessionOptions,
+) -> RuleSet {
+    let cgroup_match = Match::Cgroup(cgroup.cgroup_match());
+    let mut rules = RuleSet::new(&cgroup.session_name());
+    for family in options.families() {
+        let output_chain = Chain::new(output_chain_name, family, Table::Raw, Hook::Output)
+            .rule(
+                vec![cgroup_match.clone(), Match::Protocol(Protocol::Tcp)],
+                Target::Log,
+            )
+            .rule(
+                vec![cgroup_match.clone(), Match::Protocol(Protocol::Udp)],
+                Target::Log,
+            );
+        rules = rules.chain(output_chain);
+    }
+    rules
+}
+
+/// Rejects everything the session sends, IPv4 and IPv6, but `--allow-loopback` and
+/// `--allow-dst` destinations. With `--log-blocked` it is logged first, like in trace mode.
+pub fn block_rules(
+    output_chain_name: &str,
+    cgroup: &CgroupSpec,
+    options: &SessionOptions,
+) -> RuleSet {
+    let cgroup_match = Match::Cgroup(cgroup.cgroup_match());
+    let mut rules = RuleSet::new(&cgroup.session_name());
+    for family in [Family::V4, Family::V6] {
+        let mut chain = Chain::new(output_chain_name, family, Table::Filter, Hook::Output);
+        if options.allow_loopback {
+            chain = chain.rule(vec![Match::OutInterface("lo".into())], Target::Return);
+        }
+        let allowed = outermost(&options.allow_dst, family);
+        if !allowed.is_empty() {
+            chain = chain.rule(vec![Match::DstNet(allowed)], Target::Return);
+        }
+        if options.log_blocked {
+            chain = chain.rule(vec![cgroup_match.clone()], Target::Log);
+        }
+        rules = rules.chain(chain.rule(vec![cgroup_match.clone()], Target::Reject));
+    }
+    rules
+}
+
+/// Routing table of a tproxy session. Picking one from a range only reads the routing rules.
+pub fn route_table(options: &SessionOptions, class_id: u32) -> Result<u32> {
+    match options.route_table {
+        Some(range) => range.pick(&mut Netlink::new()?),
+        None => Ok(class_id),
+    }
+}
+
+pub fn policy_routes(
+    mark: Mark,
+    table: u32,
+    priority: Option<u32>,
+    families: &[Family],
+) -> Vec<PolicyRoute> {
+    families
+        .iter()
+        .map(|&family| PolicyRoute {
+            family,
+            mark,
+            table,
+            priority,
+        })
+        .collect()
+}
+
+/// Everything a session would set up, with the equivalent shell commands.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub mode: String,
+    pub port: u32,
+    pub backend: Backend,
+    pub cgroup: CgroupSpec,
+    /// Process moved into the cgroup, `None` for `--cgroup-path`.
+    pub pid: Option<u32>,
+    pub rules: Option<RuleSet>,
+    pub routes: Vec<PolicyRoute>,
+    pub bpf_pin_dir: Option<PathBuf>,
+    /// What only the session itself can tell, e.g. which ports are free.
+    pub notes: Vec<String>,
+    pub setup: Vec<String>,
+    pub teardown: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(
+        mode: &str,
+        port: u32,
+        backend: Backend,
+        cgroup: CgroupSpec,
+        pid: Option<u32>,
+        rules: Option<RuleSet>,
+        routes: Vec<PolicyRoute>,
+    ) -> Result<Self> {
+        let cgroup_dir = cgroup.dir()?;
+        let mut setup = vec![format!("mkdir -p {}", cgroup_dir.display())];
+        if !cgroup.hier_v2 {
+            setup.push(format!(
+                "echo {} > {}",
+                cgroup.class_id,
+                cgroup_dir.join("net_cls.classid").display()
+            ));
+        }
+        if let Some(pid) = pid {
+            setup.push(format!(
+                "echo {} > {}",
+                pid,
+                cgroup_dir.join("cgroup.procs").display()
+            ));
+        }
+        for route in &routes {
+            setup.extend(route.ip_commands("add"));
+        }
+        let mut teardown = Vec::new();
+        let mut bpf_pin_dir = None;
+        match (&rules, backend) {
+            (_, Backend::Ebpf) => {
+                let dir = bpf::pin_dir(&cgroup.session_name());
+                setup.push(format!(
+                    "# attach the cgroup socket hooks to {} and pin their map in {}",
+                    cgroup_dir.display(),
+                    dir.display()
+                ));
+                teardown.push(format!("rm -r {}", dir.display()));
+                bpf_pin_dir = Some(dir);
+            }
+            (Some(rules), backend) => {
+                setup.extend(backend.install_commands(rules));
+                teardown.extend(backend.uninstall_commands(rules));
+            }
+            (None, _) => {}
+        }
+        for route in routes.iter().rev() {
+            teardown.extend(route.ip_commands("del"));
+        }
+        teardown.push(format!("rmdir {}", cgroup_dir.display()));
+        Ok(Self {
+            mode: mode.to_owned(),
+            port,
+            backend,
+            cgroup,
+            pid,
+            rules,
+            routes,
+            bpf_pin_dir,
+            notes: Vec::new(),
+            setup,
+            teardown,
+        })
+    }
+
+    pub fn print_shell(&self) {
+        println!(
+            "# cproxy session {}: {} mode on port {} with the {:?} backend",
+            self.cgroup.class_id, self.mode, self.port, self.backend
+        );
+        for note in &self.notes {
+            println!("# {}", note);
+        }
+        println!("\n# setup");
+        for command in &self.setup {
+            println!("{}", command);
+        }
+        println!("\n# teardown");
+        for command in &self.teardown {
+            println!("{}", command);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Private `/etc/resolv.conf` and `/etc/hosts` for `--dns` and `--add-host`. The program is
+//! started in its own mount namespace with generated files bind-mounted over the originals, so
+//! it resolves differently without any packet rules, whatever resolver library it uses.
+
+use crate::journal::JOURNAL_DIR;
+use eyre::{eyre, Result, WrapErr};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+use std::ffi::{CStr, CString};
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// One `--add-host` entry, `name:ip`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostEntry {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for HostEntry {
+    type Err = eyre::Report;
+
+    /// Accepts `db.internal:10.0.0.5` and `db.internal:2001:db8::5`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, ip) = s
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| eyre!("invalid host `{}`, expected `name:ip`", s))?;
+        Ok(Self {
+            name: name.to_owned(),
+            ip: ip
+                .parse()
+                .wrap_err_with(|| format!("invalid address in `{}`", s))?,
+        })
+    }
+}
+
+/// Nameservers of a `resolv.conf`.
+pub fn nameservers(resolv_conf: &str) -> Vec<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+/// Nameservers of the host on loopback, like systemd-resolved's stub on `127.0.0.53`. The
+/// session's queries to them leave from the resolver, outside of the session.
+pub fn loopback_nameservers() -> Vec<IpAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    nameservers(&resolv_conf)
+        .into_iter()
+        .filter(|ip| ip.is_loopback())
+        .collect()
+}
+
+/// `current` with its nameservers replaced by `servers`. Search domains and options are kept.
+fn resolv_conf(current: &str, servers: &[IpAddr]) -> String {
+    let mut conf = String::from("# generated by cproxy\n");
+    for server in servers {
+        writeln!(conf, "nameserver {}", server).unwrap();
+    }
+    for line in current.lines() {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        if ["search", "domain", "options", "sortlist"].contains(&keyword) {
+            writeln!(conf, "{}", line).unwrap();
+        }
+    }
+    conf
+}
+
+/// `current` with `extra` in front, so they win over entries for the same name.
+fn hosts(current: &str, extra: &[HostEntry]) -> String {
+    let mut hosts = String::from("# added by cproxy\n");
+    for entry in extra {
+        writeln!(hosts, "{}\t{}", entry.ip, entry.name).unwrap();
+    }
+    hosts.push_str(current);
+    hosts
+}
+
+/// The generated files of one program. Dropping it removes them, the program's mounts keep
+/// them alive as long as it needs them.
+pub struct PrivateFiles {
+    dir: PathBuf,
+    /// `(generated file, file it replaces)`.
+    mounts: Vec<(PathBuf, &'static str)>,
+}
+
+impl PrivateFiles {
+    /// Generates the files for `servers` and `extra_hosts`, `None` if neither is given.
+    pub fn new(servers: &[IpAddr], extra_hosts: &[HostEntry]) -> Result<Option<Self>> {
+        if servers.is_empty() && extra_hosts.is_empty() {
+            return Ok(None);
+        }
+        let dir = Path::new(JOURNAL_DIR).join(format!("etc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+        let mut files = Self {
+            dir,
+            mounts: Vec::new(),
+        };
+        if !servers.is_empty() {
+            let current = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+            files.write(
+                "resolv.conf",
+                "/etc/resolv.conf",
+                resolv_conf(&current, servers),
+            )?;
+        }
+        if !extra_hosts.is_empty() {
+            let current = std::fs::read_to_string("/etc/hosts").unwrap_or_default();
+            files.write("hosts", "/etc/hosts", hosts(&current, extra_hosts))?;
+        }
+        Ok(Some(files))
+    }
+
+    fn write(&mut self, name: &str, target: &'static str, content: String) -> Result<()> {
+        let path = self.dir.join(name);
+        std::fs::write(&path, content)
+            .wrap_err_with(|| format!("failed to write {}", path.display()))?;
+        self.mounts.push((path, target));
+        Ok(())
+    }
+
+    /// Makes `command` enter a new mount namespace with the files in place, then switch to
+    /// `uid`/`gid`. It must not be given a uid or gid itself, as those are applied before the
+    /// mounts could be made.
+    pub fn apply(&self, command: &mut Command, uid: Option<Uid>, gid: Option<Gid>) -> Result<()> {
+        // Everything the child needs is allocated here, before the fork.
+        let mut mounts = Vec::new();
+        for (source, target) in &self.mounts {
+            mounts.push((
+                CString::new(source.as_os_str().as_bytes())?,
+                CString::new(*target)?,
+            ));
+        }
+        let root = CString::new("/")?;
+        let enter = move || -> nix::Result<()> {
+            unshare(CloneFlags::CLONE_NEWNS)?;
+            // Keep the mounts from propagating back to the host.
+            mount::<CStr, _, CStr, CStr>(
+                None,
+                root.as_c_str(),
+                None,
+                MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                None,
+            )?;
+            for (source, target) in &mounts {
+                mount::<_, _, CStr, CStr>(
+                    Some(source.as_c_str()),
+                    target.as_c_str(),
+                    None,
+                    MsFlags::MS_BIND,
+                    None,
+                )?;
+            }
+            if let Some(gid) = gid {
+                setgroups(&[])?;
+                setgid(gid)?;
+            }
+            if let Some(uid) = uid {
+                setuid(uid)?;
+            }
+            Ok(())
+        };
+        unsafe {
+            command.pre_exec(move || enter().map_err(std::io::Error::from));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PrivateFiles {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            tracing::warn!("failed to remove {}. error: {}", self.dir.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_resolv_conf_and_hosts() {
+        let current = "nameserver 127.0.0.53\noptions edns0 trust-ad\nsearch corp.example\n";
+        let servers = [
+            "10.0.0.53".parse().unwrap(),
+            "2001:db8::53".parse().unwrap(),
+        ];
+        assert_eq!(
+            nameservers(current),
+            ["127.0.0.53".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolv_conf(current, &servers),
+            "# generated by cproxy\nnameserver 10.0.0.53\nnameserver 2001:db8::53\n\
+             options edns0 trust-ad\nsearch corp.example\n"
+        );
+
+        let extra: Vec<HostEntry> = ["db.internal:10.0.0.5", "db.internal:2001:db8::5"]
+            .iter()
+            .map(|h| h.parse().unwrap())
+            .collect();
+        assert_eq!(
+            hosts("127.0.0.1\tlocalhost\n", &extra),
+            "# added by cproxy\n10.0.0.5\tdb.internal\n2001:db8::5\tdb.internal\n\
+             127.0.0.1\tlocalhost\n"
+        );
+        assert!(":10.0.0.5".parse::<HostEntry>().is_err());
+        assert!("db.internal".parse::<HostEntry>().is_err());
+    }
+}
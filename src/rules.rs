@@ -0,0 +1,445 @@
+//! Backend independent description of the packet rules installed for a session.
+//!
+//! Guards describe what they want in terms of [`Chain`]s and [`Rule`]s, and a
+//! [`crate::backend::Backend`] renders them for iptables or nftables.
+
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    pub fn localhost(&self) -> IpAddr {
+        match self {
+            Family::V4 => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Family::V6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Table {
+    Nat,
+    Mangle,
+    Raw,
+    Filter,
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Table::Nat => "nat",
+            Table::Mangle => "mangle",
+            Table::Raw => "raw",
+            Table::Filter => "filter",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hook {
+    Output,
+    Prerouting,
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Hook::Output => "OUTPUT",
+            Hook::Prerouting => "PREROUTING",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How packets of a session are recognized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CgroupMatch {
+    /// cgroup v2 path, relative to the cgroup2 mount.
+    Path(String),
+    /// cgroup v1 net_cls class id.
+    ClassId(u32),
+}
+
+/// A firewall mark. Only the bits in `mask` are matched or set, the others belong to other tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mark {
+    pub value: u32,
+    pub mask: u32,
+}
+
+impl Mark {
+    /// A mark owning all 32 bits.
+    pub fn new(value: u32) -> Self {
+        Self {
+            value,
+            mask: u32::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.mask == u32::MAX {
+            write!(f, "{}", self.value)
+        } else {
+            write!(f, "{:#x}/{:#x}", self.value, self.mask)
+        }
+    }
+}
+
+fn parse_u32(s: &str) -> Result<u32> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| eyre!("invalid number `{}`: {}", s, e))
+}
+
+impl FromStr for Mark {
+    type Err = eyre::Report;
+
+    /// Accepts `value` or `value/mask`, in decimal or `0x` hex.
+    fn from_str(s: &str) -> Result<Self> {
+        let mark = match s.split_once('/') {
+            Some((value, mask)) => Self {
+                value: parse_u32(value)?,
+                mask: parse_u32(mask)?,
+            },
+            None => Self::new(parse_u32(s)?),
+        };
+        if mark.value & !mark.mask != 0 || mark.value == 0 {
+            return Err(eyre!(
+                "invalid mark `{}`, the value must be non-zero and inside the mask",
+                s
+            ));
+        }
+        Ok(mark)
+    }
+}
+
+/// An address range like `10.0.0.0/8`. The host bits of `ip` are always zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cidr {
+    pub ip: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn family(&self) -> Family {
+        Family::of(&self.ip)
+    }
+
+    /// Whether `other` lies completely inside this range.
+    pub fn contains(&self, other: &Cidr) -> bool {
+        if self.family() != other.family() || self.prefix > other.prefix {
+            return false;
+        }
+        let (ip, other_ip, bits) = match (self.ip, other.ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+            (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix as u32;
+        ip.checked_shr(shift).unwrap_or(0) == other_ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = eyre::Report;
+
+    /// Accepts `10.0.0.0/8`, `fc00::/7` or a single address.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || eyre!("invalid address range `{}`, expected e.g. `10.0.0.0/8`", s);
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (
+                ip.parse::<IpAddr>().map_err(|_| invalid())?,
+                Some(prefix.parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (s.parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let ip = match ip {
+            IpAddr::V4(ip) => {
+                let prefix = prefix.unwrap_or(32);
+                if prefix > 32 {
+                    return Err(invalid());
+                }
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                return Ok(Self {
+                    ip: IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask)),
+                    prefix,
+                });
+            }
+            IpAddr::V6(ip) => ip,
+        };
+        let prefix = prefix.unwrap_or(128);
+        if prefix > 128 {
+            return Err(invalid());
+        }
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        Ok(Self {
+            ip: IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask)),
+            prefix,
+        })
+    }
+}
+
+/// Inclusive range of ports, a single port if `start == end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = eyre::Report;
+
+    /// Accepts `443`, `8000-8080` or `8000:8080`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!(
+                "invalid port range `{}`, expected e.g. `443` or `8000-8080`",
+                s
+            )
+        };
+        let (start, end) = match s.split_once(['-', ':']) {
+            Some((start, end)) => (start, end),
+            None => (s, s),
+        };
+        let start = start.parse::<u16>().map_err(|_| invalid())?;
+        let end = end.parse::<u16>().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Traffic to `dports` goes to the proxy on `port` instead, from `--port-map`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub dports: Vec<PortRange>,
+    pub port: u16,
+}
+
+impl FromStr for PortMapping {
+    type Err = eyre::Report;
+
+    /// Accepts `80,443=8118` or `8000-8080=3128`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (dports, port) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("invalid port mapping `{}`, expected e.g. `80,443=8118`", s))?;
+        Ok(Self {
+            dports: dports.split(',').map(str::parse).collect::<Result<_>>()?,
+            port: port
+                .parse()
+                .ok()
+                .filter(|&port| port != 0)
+                .ok_or_else(|| eyre!("invalid proxy port `{}`", port))?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Match {
+    Protocol(Protocol),
+    OutInterface(String),
+    Cgroup(CgroupMatch),
+    Mark(Mark),
+    DstPort(u16),
+    /// Destination in any of the ranges, all of the chain's family.
+    DstNet(Vec<Cidr>),
+    /// Destination port in any of the ranges, which don't overlap.
+    DstPorts(Vec<PortRange>),
+    /// Destination in the named [`IpSet`] of the rule set.
+    DstSet(String),
+    /// Every `every`th packet reaching the rule, starting with the `packet`th.
+    Nth {
+        every: u32,
+        packet: u32,
+    },
+}
+
+/// Address to rewrite a destination to. Without a port, the original port is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Destination {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}", SocketAddr::new(self.ip, port)),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = eyre::Report;
+
+    /// Accepts `1.1.1.1`, `1.1.1.1:53`, `2606:4700::1111` and `[2606:4700::1111]:53`.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self { ip, port: None });
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+        Err(eyre!("invalid address `{}`, expected `ip` or `ip:port`", s))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    Return,
+    Redirect { port: u32 },
+    TProxy { ip: IpAddr, port: u32 },
+    SetMark(Mark),
+    Dnat(Destination),
+    Log,
+    Reject,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub matches: Vec<Match>,
+    pub target: Target,
+}
+
+impl Rule {
+    pub fn new(matches: Vec<Match>, target: Target) -> Self {
+        Self { matches, target }
+    }
+}
+
+/// A chain attached to a builtin hook of a table.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chain {
+    pub name: String,
+    pub family: Family,
+    pub table: Table,
+    pub hook: Hook,
+    pub rules: Vec<Rule>,
+}
+
+impl Chain {
+    pub fn new(name: &str, family: Family, table: Table, hook: Hook) -> Self {
+        Self {
+            name: name.to_owned(),
+            family,
+            table,
+            hook,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn rule(mut self, matches: Vec<Match>, target: Target) -> Self {
+        self.rules.push(Rule::new(matches, target));
+        self
+    }
+}
+
+/// Traffic that hit the rules of a chain, `RETURN` rules not included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Kernel set of address ranges, an ipset or a named nftables set. Too many ranges for a rule each.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpSet {
+    pub name: String,
+    pub family: Family,
+    /// May overlap, the kernel merges them.
+    pub nets: Vec<Cidr>,
+    /// Filled with single addresses while the session runs, each expiring after its own timeout.
+    #[serde(default)]
+    pub timeout: bool,
+}
+
+/// All the chains installed for one session.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleSet {
+    /// Unique name of the session, used as the nftables table name.
+    pub name: String,
+    pub chains: Vec<Chain>,
+    /// Sets the chains match against, created before and removed after them.
+    #[serde(default)]
+    pub sets: Vec<IpSet>,
+}
+
+impl RuleSet {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            chains: Vec::new(),
+            sets: Vec::new(),
+        }
+    }
+
+    pub fn chain(mut self, chain: Chain) -> Self {
+        self.chains.push(chain);
+        self
+    }
+}
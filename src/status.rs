@@ -0,0 +1,132 @@
+//! `cproxy status`: the sessions on this host, from their journals and the kernel.
+
+use crate::backend::Backend;
+use crate::journal::{self, Session};
+use crate::rules::{Counters, Family, Hook, Mark, Table};
+use cgroups_rs::Cgroup;
+use eyre::Result;
+use serde::Serialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize)]
+pub struct ChainStatus {
+    pub name: String,
+    pub family: Family,
+    pub table: Table,
+    pub hook: Hook,
+    /// Missing if the chain could not be read back from the kernel.
+    pub counters: Option<Counters>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionStatus {
+    pub owner: u32,
+    /// False for sessions that died without cleaning up, see `cproxy cleanup`.
+    pub running: bool,
+    pub mode: String,
+    pub port: u32,
+    pub backend: Option<Backend>,
+    pub cgroup: Option<String>,
+    pub class_id: u32,
+    /// fwmark and routing table of the policy routing in tproxy mode.
+    pub mark: Option<Mark>,
+    pub route_table: Option<u32>,
+    pub chains: Vec<ChainStatus>,
+    pub bpf_pin_dir: Option<PathBuf>,
+    pub pids: Vec<u64>,
+}
+
+impl SessionStatus {
+    fn new(session: Session) -> Self {
+        let mut chains = Vec::new();
+        if let (Some(backend), Some(rules)) = (session.backend, &session.rules) {
+            let counters = backend.counters(rules).unwrap_or_else(|e| {
+                tracing::debug!("failed to read counters of {}. error: {}", rules.name, e);
+                Vec::new()
+            });
+            for (i, chain) in rules.chains.iter().enumerate() {
+                chains.push(ChainStatus {
+                    name: chain.name.clone(),
+                    family: chain.family,
+                    table: chain.table,
+                    hook: chain.hook,
+                    counters: counters.get(i).copied(),
+                });
+            }
+        }
+        let pids = session
+            .cgroup
+            .as_ref()
+            .map(|path| Cgroup::load(cgroups_rs::hierarchies::auto(), path))
+            .filter(|cg| cg.exists())
+            .map(|cg| cg.procs().into_iter().map(|pid| pid.pid).collect())
+            .unwrap_or_default();
+        Self {
+            owner: session.owner,
+            running: session.owner_alive(),
+            mode: session.mode,
+            port: session.port,
+            backend: session.backend,
+            cgroup: session.cgroup,
+            class_id: session.class_id,
+            mark: session.routes.first().map(|route| route.mark),
+            route_table: session.routes.first().map(|route| route.table),
+            chains,
+            bpf_pin_dir: session.bpf_pin_dir,
+            pids,
+        }
+    }
+
+    fn print(&self) {
+        let state = if self.running { "running" } else { "stale" };
+        println!("session {} ({})", self.class_id, state);
+        println!("  owner:    {}", self.owner);
+        println!("  mode:     {}", self.mode);
+        println!("  port:     {}", self.port);
+        if let Some(backend) = self.backend {
+            println!("  backend:  {:?}", backend);
+        }
+        if let Some(cgroup) = &self.cgroup {
+            println!("  cgroup:   {}", cgroup);
+        }
+        println!("  class id: {}", self.class_id);
+        if let Some(mark) = self.mark {
+            println!("  mark:     {}", mark);
+        }
+        if let Some(table) = self.route_table {
+            println!("  table:    {}", table);
+        }
+        for chain in &self.chains {
+            let counters = match chain.counters {
+                Some(c) => format!("{} packets, {} bytes", c.packets, c.bytes),
+                None => "no counters".to_owned(),
+            };
+            println!(
+                "  chain:    {} ({:?} {} {}): {}",
+                chain.name, chain.family, chain.table, chain.hook, counters
+            );
+        }
+        if let Some(dir) = &self.bpf_pin_dir {
+            println!("  bpf pins: {}", dir.display());
+        }
+        let pids: Vec<String> = self.pids.iter().map(|pid| pid.to_string()).collect();
+        println!("  pids:     {}", pids.join(" "));
+    }
+}
+
+pub fn run(json: bool) -> Result<()> {
+    let sessions: Vec<SessionStatus> = journal::load_all()?
+        .into_iter()
+        .map(|(_, session)| SessionStatus::new(session))
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&sessions)?);
+    } else if sessions.is_empty() {
+        println!("no cproxy sessions");
+    } else {
+        for session in &sessions {
+            session.print();
+        }
+    }
+    Ok(())
+}
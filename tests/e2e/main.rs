@@ -0,0 +1,551 @@
+//! End-to-end tests of every mode against a stub proxy, in throwaway network namespaces. They
+//! need root and `ip`, so they are ignored by default, and skip the backends this host lacks
+//! unless `CI` is set:
+//!
+//! ```bash
+//! sudo -E env "PATH=$PATH" cargo test --test e2e -- --ignored
+//! ```
+
+mod netns;
+mod stub;
+
+use netns::{Topology, REMOTE_IP};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+use stub::{OrigDst, DNS_PORT, ECHO_PORT, PROXY_PORT};
+
+/// cproxy sessions share journals and bpf pins on the host, so the tests run one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Sends a line over TCP and a datagram to the DNS port of the destination, prints the answers.
+/// `read` takes a byte at a time and would drop the rest of a datagram, so UDP uses `head`.
+fn client_script() -> String {
+    format!(
+        "exec 3<>/dev/tcp/{ip}/{tcp}; echo hello >&3; read -t 3 line <&3; echo \"tcp: $line\"; \
+         exec 4<>/dev/udp/{ip}/{dns}; echo query >&4; echo \"udp: $(timeout 3 head -n 1 <&4)\"",
+        ip = REMOTE_IP,
+        tcp = ECHO_PORT,
+        dns = DNS_PORT,
+    )
+}
+
+const DIRECT: &str = "tcp: hello\nudp: query\n";
+
+fn proxied() -> String {
+    format!(
+        "tcp: proxied {}:{} hello\nudp: proxied query\n",
+        REMOTE_IP, ECHO_PORT
+    )
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Why this host can't run the tests of `backend`, if it can't.
+fn missing(backend: Option<&str>) -> Option<String> {
+    if !nix::unistd::geteuid().is_root() {
+        return Some("not running as root".to_owned());
+    }
+    if !in_path("ip") {
+        return Some("`ip` not found".to_owned());
+    }
+    let needs = match backend {
+        Some("iptables") => vec!["iptables-restore", "ip6tables-restore"],
+        Some("nftables") => vec!["nft"],
+        _ => vec![],
+    };
+    if let Some(program) = needs.iter().find(|p| !in_path(p)) {
+        return Some(format!("`{}` not found", program));
+    }
+    if backend == Some("ebpf") && !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        return Some("no unified cgroup v2 hierarchy".to_owned());
+    }
+    None
+}
+
+macro_rules! skip_unless_supported {
+    ($backend:expr) => {
+        if let Some(reason) = missing($backend) {
+            // A CI job that tests nothing must not pass.
+            if std::env::var_os("CI").is_some() {
+                panic!("can't run in CI: {}", reason);
+            }
+            eprintln!("skipping: {}", reason);
+            return;
+        }
+    };
+}
+
+/// Runs `script` under cproxy in the client namespace and returns its output.
+fn run_cproxy(topology: &Topology, args: &[&str], script: &str) -> String {
+    let output = topology
+        .client
+        .command(env!("CARGO_BIN_EXE_cproxy"))
+        .args(args)
+        .args(["--port", &PROXY_PORT.to_string(), "--", "bash", "-c"])
+        .arg(script)
+        .output()
+        .expect("failed to run cproxy");
+    assert!(
+        output.status.success(),
+        "cproxy failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Checks that nothing of the session is left in the client namespace.
+fn assert_torn_down(topology: &Topology, backend: &str) {
+    let (program, args): (&str, &[&str]) = match backend {
+        "iptables" => ("iptables-save", &[]),
+        "nftables" => ("nft", &["list", "ruleset"]),
+        _ => return,
+    };
+    let output = topology
+        .client
+        .command(program)
+        .args(args)
+        .output()
+        .unwrap();
+    let ruleset = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        !ruleset.contains("cp_") && !ruleset.contains("cproxy_"),
+        "leftover rules: {}",
+        ruleset
+    );
+}
+
+fn check(mode: &str, backend: &str, orig: OrigDst, extra: &[&str], expected: &str) {
+    check_script(mode, backend, orig, extra, &client_script(), expected);
+}
+
+fn check_script(
+    mode: &str,
+    backend: &str,
+    orig: OrigDst,
+    extra: &[&str],
+    script: &str,
+    expected: &str,
+) {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let topology = Topology::new();
+    stub::destination(&topology.remote);
+    stub::proxy(&topology.client, orig);
+    let mut args = vec!["--mode", mode, "--backend", backend];
+    args.extend(extra);
+    assert_eq!(run_cproxy(&topology, &args, script), expected);
+    assert_torn_down(&topology, backend);
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn harness_without_cproxy() {
+    skip_unless_supported!(None);
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let topology = Topology::new();
+    stub::destination(&topology.remote);
+    stub::proxy(&topology.client, OrigDst::Redirect);
+    let output = topology
+        .client
+        .command("bash")
+        .arg("-c")
+        .arg(client_script())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), DIRECT);
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn redirect_iptables() {
+    skip_unless_supported!(Some("iptables"));
+    check(
+        "redirect",
+        "iptables",
+        OrigDst::Redirect,
+        &["--redirect-dns"],
+        &proxied(),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn redirect_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check(
+        "redirect",
+        "nftables",
+        OrigDst::Redirect,
+        &["--redirect-dns"],
+        &proxied(),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn redirect_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    check(
+        "redirect",
+        "ebpf",
+        OrigDst::Bpf,
+        &["--redirect-dns"],
+        &proxied(),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn tproxy_iptables() {
+    skip_unless_supported!(Some("iptables"));
+    check("tproxy", "iptables", OrigDst::TProxy, &[], &proxied());
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn tproxy_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check("tproxy", "nftables", OrigDst::TProxy, &[], &proxied());
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn trace_iptables() {
+    skip_unless_supported!(Some("iptables"));
+    check("trace", "iptables", OrigDst::Redirect, &[], DIRECT);
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn trace_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check("trace", "nftables", OrigDst::Redirect, &[], DIRECT);
+}
+
+/// Tries to reach the destination over TCP and UDP, without failing if it can't.
+fn block_script() -> String {
+    format!(
+        "for proto in tcp udp; do (echo ping >/dev/$proto/{ip}/{port}) 2>/dev/null \
+         && echo \"$proto: sent\" || echo \"$proto: refused\"; done",
+        ip = REMOTE_IP,
+        port = ECHO_PORT,
+    )
+}
+
+fn check_block(backend: &str) {
+    let allowed = format!("{}/32", REMOTE_IP);
+    for (extra, expected) in [
+        (vec![], "tcp: refused\nudp: refused\n"),
+        (vec!["--log-blocked"], "tcp: refused\nudp: refused\n"),
+        (vec!["--allow-dst", &allowed], "tcp: sent\nudp: sent\n"),
+    ] {
+        check_script(
+            "block",
+            backend,
+            OrigDst::Redirect,
+            &extra,
+            &block_script(),
+            expected,
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn block_iptables() {
+    skip_unless_supported!(Some("iptables"));
+    check_block("iptables");
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn block_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check_block("nftables");
+}
+
+/// TCP to a bypassed destination goes out directly, DNS is still proxied.
+fn bypassed() -> String {
+    "tcp: hello\nudp: proxied query\n".to_owned()
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn bypass_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check(
+        "redirect",
+        "nftables",
+        OrigDst::Redirect,
+        &["--redirect-dns", "--bypass", REMOTE_IP],
+        &bypassed(),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn bypass_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    check(
+        "redirect",
+        "ebpf",
+        OrigDst::Bpf,
+        &["--redirect-dns", "--bypass", REMOTE_IP],
+        &bypassed(),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn only_dport_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    let below = format!("1-{}", ECHO_PORT - 1);
+    let around = format!("{}-{}", ECHO_PORT - 1, ECHO_PORT + 1);
+    for (ports, expected) in [(below, bypassed()), (around, proxied())] {
+        check(
+            "redirect",
+            "ebpf",
+            OrigDst::Bpf,
+            &["--redirect-dns", "--only-dport", &ports],
+            &expected,
+        );
+    }
+}
+
+/// TCP to the echo port is mapped to the proxy, while the default TCP port has nothing
+/// listening.
+fn port_map_args() -> Vec<String> {
+    vec![
+        "--redirect-dns".to_owned(),
+        "--tcp-port".to_owned(),
+        "9".to_owned(),
+        "--port-map".to_owned(),
+        format!("{},{}={}", ECHO_PORT - 1, ECHO_PORT, PROXY_PORT),
+    ]
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn port_map_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    let args = port_map_args();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    check("redirect", "nftables", OrigDst::Redirect, &args, &proxied());
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn port_map_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    let args = port_map_args();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    check("redirect", "ebpf", OrigDst::Bpf, &args, &proxied());
+}
+
+/// Sends a line over TCP to the DNS port.
+fn dns_over_tcp_script() -> String {
+    format!(
+        "exec 3<>/dev/tcp/{ip}/{dns}; echo hello >&3; read -t 3 line <&3; echo \"tcp: $line\"",
+        ip = REMOTE_IP,
+        dns = DNS_PORT,
+    )
+}
+
+/// DNS over TCP goes to the proxy as well, even to a bypassed resolver.
+fn check_dns_over_tcp(backend: &str, orig: OrigDst) {
+    check_script(
+        "redirect",
+        backend,
+        orig,
+        &["--redirect-dns", "--bypass", REMOTE_IP],
+        &dns_over_tcp_script(),
+        &format!("tcp: proxied {}:{} hello\n", REMOTE_IP, DNS_PORT),
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn dns_over_tcp_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check_dns_over_tcp("nftables", OrigDst::Redirect);
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn dns_over_tcp_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    check_dns_over_tcp("ebpf", OrigDst::Bpf);
+}
+
+/// Asks a stub resolver on loopback like systemd-resolved's, nothing listens there.
+const STUB_RESOLVER_SCRIPT: &str = "exec 4<>/dev/udp/127.0.0.53/53; echo query >&4; \
+     echo \"udp: $(timeout 3 head -n 1 <&4)\"";
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn stub_resolver_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    check_script(
+        "redirect",
+        "nftables",
+        OrigDst::Redirect,
+        &["--redirect-dns"],
+        STUB_RESOLVER_SCRIPT,
+        "udp: proxied query\n",
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn stub_resolver_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    check_script(
+        "redirect",
+        "ebpf",
+        OrigDst::Bpf,
+        &["--redirect-dns"],
+        STUB_RESOLVER_SCRIPT,
+        "udp: proxied query\n",
+    );
+}
+
+/// The program sees its own nameserver and hosts entry, the host keeps its files.
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn private_resolv_conf_and_hosts_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let hosts = std::fs::read_to_string("/etc/hosts").unwrap();
+    check_script(
+        "redirect",
+        "ebpf",
+        OrigDst::Bpf,
+        &["--dns", "192.0.2.53", "--add-host", "db.internal:192.0.2.5"],
+        "grep nameserver /etc/resolv.conf; getent hosts db.internal",
+        "nameserver 192.0.2.53\n192.0.2.5       db.internal\n",
+    );
+    assert_eq!(
+        std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default(),
+        resolv_conf
+    );
+    assert_eq!(std::fs::read_to_string("/etc/hosts").unwrap(), hosts);
+}
+
+/// Sends a datagram to the echo port, which redirect mode can't proxy, then DNS as usual.
+fn udp_policy_script() -> String {
+    format!(
+        "(echo ping >/dev/udp/{ip}/{port}) 2>/dev/null && echo \"udp: sent\" \
+         || echo \"udp: refused\"; {client}",
+        ip = REMOTE_IP,
+        port = ECHO_PORT,
+        client = client_script(),
+    )
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn udp_policy_nftables() {
+    skip_unless_supported!(Some("nftables"));
+    let expected = format!("udp: refused\n{}", proxied());
+    check_script(
+        "redirect",
+        "nftables",
+        OrigDst::Redirect,
+        &["--redirect-dns", "--udp-policy", "reject"],
+        &udp_policy_script(),
+        &expected,
+    );
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn udp_policy_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    for (policy, udp) in [("direct", "sent"), ("reject", "refused")] {
+        check_script(
+            "redirect",
+            "ebpf",
+            OrigDst::Bpf,
+            &["--redirect-dns", "--udp-policy", policy],
+            &udp_policy_script(),
+            &format!("udp: {}\n{}", udp, proxied()),
+        );
+    }
+}
+
+/// Dual-stack IPv6 sockets reach IPv4 hosts through mapped addresses like `::ffff:198.18.0.2`,
+/// with or without the kill switch they go through the proxy all the same.
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn mapped_ipv4_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    let script = udp_policy_script().replace(REMOTE_IP, &format!("::ffff:{}", REMOTE_IP));
+    for leak in [&[][..], &["--allow-ipv6-leak"]] {
+        let mut args = vec!["--redirect-dns", "--udp-policy", "reject"];
+        args.extend(leak);
+        check_script(
+            "redirect",
+            "ebpf",
+            OrigDst::Bpf,
+            &args,
+            &script,
+            &format!("udp: refused\n{}", proxied()),
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs root, run with --ignored"]
+fn bypass_file_ebpf() {
+    skip_unless_supported!(Some("ebpf"));
+    let list = std::env::temp_dir().join(format!("cproxy-e2e-bypass-{}", std::process::id()));
+    std::fs::write(&list, format!("# lab\n2001:db8::/32\n{}/32\n", REMOTE_IP)).unwrap();
+    let list_path = list.to_str().unwrap().to_owned();
+    for (option, expected) in [
+        ("--bypass-file", bypassed()),
+        ("--only-dst-file", proxied()),
+    ] {
+        check(
+            "redirect",
+            "ebpf",
+            OrigDst::Bpf,
+            &["--redirect-dns", option, &list_path],
+            &expected,
+        );
+    }
+    std::fs::remove_file(&list).unwrap();
+}
+
+/// `--dry-run` neither binds sockets nor reads what only root may read, so anyone can run it.
+#[test]
+fn dry_run_unprivileged() {
+    let dir = std::env::temp_dir().join(format!("cproxy-e2e-dry-run-{}", std::process::id()));
+    let mut command = Command::new(env!("CARGO_BIN_EXE_cproxy"));
+    if nix::unistd::geteuid().is_root() {
+        // The build directory is often only readable by root, so nobody runs a copy.
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::copy(env!("CARGO_BIN_EXE_cproxy"), dir.join("cproxy")).unwrap();
+        command = Command::new(dir.join("cproxy"));
+        command.current_dir(&dir).uid(65534).gid(65534);
+    }
+    let output = command
+        .args(["--dry-run", "--backend", "iptables"])
+        .args(["--proxy-domain", "example.com", "--", "true"])
+        .output()
+        .expect("failed to run cproxy");
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(
+        output.status.success(),
+        "cproxy failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("iptables-restore"), "{}", stdout);
+}
@@ -0,0 +1,114 @@
+//! Throwaway network namespaces: `client` runs cproxy and the stub proxy, `remote` the
+//! destination, connected by a veth pair.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const CLIENT_IP: &str = "198.18.0.1";
+pub const REMOTE_IP: &str = "198.18.0.2";
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .expect("failed to run ip");
+    assert!(status.success(), "ip {} failed", args.join(" "));
+}
+
+fn setns(fd: i32) -> std::io::Result<()> {
+    if unsafe { libc::setns(fd, libc::CLONE_NEWNET) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+pub struct Netns {
+    pub name: String,
+    file: File,
+}
+
+impl Netns {
+    fn new(name: String) -> Self {
+        ip(&["netns", "add", &name]);
+        let file = File::open(format!("/run/netns/{}", name)).expect("failed to open netns");
+        ip(&["-n", &name, "link", "set", "lo", "up"]);
+        Self { name, file }
+    }
+
+    /// Runs `f` on a thread inside the namespace. Threads spawned by `f` stay inside as well.
+    pub fn spawn(&self, f: impl FnOnce() + Send + 'static) {
+        let file = self.file.try_clone().expect("failed to clone netns fd");
+        std::thread::spawn(move || {
+            setns(file.as_raw_fd()).expect("failed to enter netns");
+            f()
+        });
+    }
+
+    /// A command that runs inside the namespace, without the mount namespace `ip netns exec`
+    /// sets up, so cproxy still sees the host's cgroup and bpf filesystems.
+    pub fn command(&self, program: &str) -> Command {
+        let fd = self.file.as_raw_fd();
+        let mut command = Command::new(program);
+        unsafe {
+            command.pre_exec(move || setns(fd));
+        }
+        command
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        let _ = Command::new("ip")
+            .args(["netns", "del", &self.name])
+            .status();
+    }
+}
+
+pub struct Topology {
+    pub client: Netns,
+    pub remote: Netns,
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let prefix = format!(
+            "cproxy-e2e-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+        let client = Netns::new(format!("{}-c", prefix));
+        let remote = Netns::new(format!("{}-r", prefix));
+        ip(&[
+            "-n",
+            &client.name,
+            "link",
+            "add",
+            "veth0",
+            "type",
+            "veth",
+            "peer",
+            "name",
+            "veth0",
+            "netns",
+            &remote.name,
+        ]);
+        for (netns, addr) in [(&client, CLIENT_IP), (&remote, REMOTE_IP)] {
+            ip(&[
+                "-n",
+                &netns.name,
+                "addr",
+                "add",
+                &format!("{}/24", addr),
+                "dev",
+                "veth0",
+            ]);
+            ip(&["-n", &netns.name, "link", "set", "veth0", "up"]);
+        }
+        Self { client, remote }
+    }
+}
@@ -0,0 +1,262 @@
+//! The destination and a stub transparent proxy. The proxy forwards TCP to the original
+//! destination and answers `proxied <original destination> <echo>`, UDP is answered with
+//! `proxied <payload>` directly, so the client can tell which way its traffic went.
+
+use crate::netns::Netns;
+use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+
+pub const PROXY_PORT: u16 = 12345;
+pub const ECHO_PORT: u16 = 7;
+pub const DNS_PORT: u16 = 53;
+
+/// How the proxy learns where a connection was going.
+#[derive(Clone, Copy, Debug)]
+pub enum OrigDst {
+    /// `SO_ORIGINAL_DST` of a REDIRECTed connection.
+    Redirect,
+    /// The local address of a TPROXY socket.
+    TProxy,
+    /// The pinned `orig_dst` map of the ebpf backend.
+    Bpf,
+}
+
+/// Starts `serve` on a thread in `netns`, once `bind` succeeded there.
+fn serve<T: Send + 'static>(
+    netns: &Netns,
+    bind: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+    serve: impl FnOnce(T) + Send + 'static,
+) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    netns.spawn(move || match bind() {
+        Ok(socket) => {
+            sender.send(Ok(())).unwrap();
+            serve(socket)
+        }
+        Err(e) => sender.send(Err(e)).unwrap(),
+    });
+    receiver
+        .recv()
+        .unwrap()
+        .expect("failed to bind test server");
+}
+
+fn read_line(stream: &TcpStream) -> std::io::Result<String> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(line)
+}
+
+/// Echoes TCP lines on [`ECHO_PORT`] and [`DNS_PORT`], and UDP datagrams on [`DNS_PORT`].
+pub fn destination(netns: &Netns) {
+    for port in [ECHO_PORT, DNS_PORT] {
+        serve(
+            netns,
+            move || TcpListener::bind(("0.0.0.0", port)),
+            |listener| {
+                for mut stream in listener.incoming().flatten() {
+                    std::thread::spawn(move || {
+                        if let Ok(line) = read_line(&stream) {
+                            let _ = stream.write_all(line.as_bytes());
+                        }
+                    });
+                }
+            },
+        );
+    }
+    serve(
+        netns,
+        || UdpSocket::bind(("0.0.0.0", DNS_PORT)),
+        |socket| {
+            let mut buf = [0; 1500];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&buf[..len], peer);
+            }
+        },
+    );
+}
+
+fn transparent(ty: SockType, addr: SocketAddrV4, orig_dst: bool) -> std::io::Result<OwnedFd> {
+    let fd = socket::socket(AddressFamily::Inet, ty, SockFlag::empty(), None)?;
+    socket::setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    socket::setsockopt(&fd, sockopt::IpTransparent, &true)?;
+    if orig_dst {
+        socket::setsockopt(&fd, sockopt::Ipv4OrigDstAddr, &true)?;
+    }
+    socket::bind(fd.as_raw_fd(), &SockaddrIn::from(addr))?;
+    if ty == SockType::Stream {
+        socket::listen(&fd, socket::Backlog::new(16)?)?;
+    }
+    Ok(fd)
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::from((
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+fn redirect_orig_dst(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(to_socket_addr(&addr))
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u64; 4]) -> std::io::Result<libc::c_long> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr.as_mut_ptr(),
+            std::mem::size_of_val(attr),
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+/// Looks the client's source port up in the `orig_dst` map of every ebpf session.
+fn bpf_orig_dst(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+    const BPF_OBJ_GET: libc::c_long = 7;
+    let key = stream.peer_addr()?.port() as u32;
+    for entry in std::fs::read_dir("/sys/fs/bpf")?.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("cproxy_") {
+            continue;
+        }
+        let path = std::ffi::CString::new(entry.path().join("orig_dst").to_string_lossy().as_ref())
+            .unwrap();
+        let mut attr = [path.as_ptr() as u64, 0, 0, 0];
+        let map = match bpf(BPF_OBJ_GET, &mut attr) {
+            Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd as i32) },
+            Err(_) => continue,
+        };
+        let mut value = [0u8; 8];
+        let mut attr = [
+            map.as_raw_fd() as u64,
+            &key as *const u32 as u64,
+            value.as_mut_ptr() as u64,
+            0,
+        ];
+        if bpf(BPF_MAP_LOOKUP_ELEM, &mut attr).is_ok() {
+            let ip = Ipv4Addr::new(value[0], value[1], value[2], value[3]);
+            return Ok(SocketAddr::from((
+                ip,
+                u16::from_be_bytes([value[4], value[5]]),
+            )));
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "connection is not in any orig_dst map",
+    ))
+}
+
+fn forward(mut stream: TcpStream, orig: OrigDst) -> std::io::Result<()> {
+    let dst = match orig {
+        OrigDst::Redirect => redirect_orig_dst(&stream)?,
+        OrigDst::TProxy => stream.local_addr()?,
+        OrigDst::Bpf => bpf_orig_dst(&stream)?,
+    };
+    let line = read_line(&stream)?;
+    let mut upstream = TcpStream::connect(dst)?;
+    upstream.write_all(line.as_bytes())?;
+    let echo = read_line(&upstream)?;
+    write!(stream, "proxied {} {}", dst, echo)
+}
+
+fn proxied(payload: &[u8]) -> Vec<u8> {
+    [b"proxied ".as_ref(), payload].concat()
+}
+
+/// Answers one datagram received by a TPROXY socket, from the address it was sent to.
+fn answer_tproxy(socket: &OwnedFd) -> std::io::Result<()> {
+    let mut buf = [0; 1500];
+    let mut iov = [std::io::IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!(libc::sockaddr_in);
+    let msg = socket::recvmsg::<SockaddrIn>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        socket::MsgFlags::empty(),
+    )?;
+    let peer = msg.address.expect("datagram without sender");
+    let dst = msg
+        .cmsgs()?
+        .find_map(|cmsg| match cmsg {
+            socket::ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(to_socket_addr(&addr)),
+            _ => None,
+        })
+        .expect("datagram without original destination");
+    let len = msg.bytes;
+    let dst = match dst {
+        SocketAddr::V4(dst) => dst,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let reply = transparent(SockType::Datagram, dst, false)?;
+    let reply = unsafe { UdpSocket::from_raw_fd(reply.into_raw_fd()) };
+    reply.send_to(
+        &proxied(&buf[..len]),
+        SocketAddr::from((peer.ip(), peer.port())),
+    )?;
+    Ok(())
+}
+
+/// Starts the stub proxy on `127.0.0.1:PROXY_PORT` in `netns`.
+pub fn proxy(netns: &Netns, orig: OrigDst) {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, PROXY_PORT);
+    serve(
+        netns,
+        move || {
+            let fd = transparent(SockType::Stream, addr, false)?;
+            Ok(unsafe { TcpListener::from_raw_fd(fd.into_raw_fd()) })
+        },
+        move |listener| {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    if let Err(e) = forward(stream, orig) {
+                        eprintln!("stub proxy failed to forward: {}", e);
+                    }
+                });
+            }
+        },
+    );
+    match orig {
+        OrigDst::TProxy => serve(
+            netns,
+            move || transparent(SockType::Datagram, addr, true),
+            |socket| loop {
+                if let Err(e) = answer_tproxy(&socket) {
+                    eprintln!("stub proxy failed to answer: {}", e);
+                }
+            },
+        ),
+        _ => serve(
+            netns,
+            move || UdpSocket::bind(addr),
+            |socket| {
+                let mut buf = [0; 1500];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                    let _ = socket.send_to(&proxied(&buf[..len]), peer);
+                }
+            },
+        ),
+    }
+}